@@ -1,3 +1,16 @@
 fn main() {
-    tauri_build::build()
+    tauri_build::try_build(
+        tauri_build::Attributes::new().app_manifest(
+            tauri_build::AppManifest::new().commands(&[
+                "get_sandbox_url",
+                "get_app_doc_id",
+                "open_app",
+                "answer_network_prompt",
+                "set_egress",
+                "get_egress",
+                "set_app_offline",
+            ]),
+        ),
+    )
+    .expect("failed to run tauri-build")
 }
@@ -1,13 +1,12 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, lookup_host},
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 
 use super::error::Error;
+use crate::resolve::Resolver;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Addr {
     Ip(IpAddr, u16),
     Domain(String, u16),
@@ -21,7 +20,7 @@ impl TryFrom<Addr> for SocketAddr {
         match value {
             Addr::Ip(ip_addr, port) => Ok(SocketAddr::new(ip_addr, port)),
             Addr::Domain(_, _) => Err(Error::Internal(
-                "tried to convert an addr of type domain to a SocketAddr. Call resolve_dns first before calling the type conversion",
+                "tried to convert an addr of type domain to a SocketAddr. Resolve it first before calling the type conversion",
             )),
             Addr::Null => Err(Error::Internal(
                 "tried to convert a null address to a SocketAddr.",
@@ -30,6 +29,17 @@ impl TryFrom<Addr> for SocketAddr {
     }
 }
 
+/// `host:port`, with IPv6 addresses in brackets.
+impl Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Addr::Ip(ip, port) => SocketAddr::new(*ip, *port).fmt(f),
+            Addr::Domain(domain, port) => write!(f, "{domain}:{port}"),
+            Addr::Null => f.write_str("null"),
+        }
+    }
+}
+
 impl Addr {
     pub fn from_ip_addr(ip_addr: IpAddr, port: u16) -> Self {
         Self::Ip(ip_addr, port)
@@ -56,124 +66,33 @@ impl Addr {
         Ok(Self::Domain(domain, port))
     }
 
-    pub async fn from_stream(stream: &mut TcpStream) -> Result<Self, Error> {
-        match stream.read_u8().await? {
-            0x01 => Self::ipv4_from_stream(stream).await,
-            0x03 => Self::domain_from_stream(stream).await,
-            0x04 => Self::ipv6_from_stream(stream).await,
-            _ => Err(Error::AddressTypeNotSupported),
+    pub fn port(&self) -> u16 {
+        match self {
+            Addr::Ip(_, port) | Addr::Domain(_, port) => *port,
+            Addr::Null => 0,
         }
     }
 
-    pub async fn ipv4_from_stream(
-        stream: &mut TcpStream,
-    ) -> Result<Self, Error> {
-        let mut ipv4_bytes = [0u8; 4];
-        stream.read_exact(&mut ipv4_bytes).await?;
-        Ok(Self::Ip(
-            IpAddr::V4(Ipv4Addr::from_bits(stream.read_u32().await?)),
-            stream.read_u16().await?,
-        ))
-    }
-
-    pub async fn ipv6_from_stream(
-        stream: &mut TcpStream,
-    ) -> Result<Self, Error> {
-        Ok(Self::Ip(
-            IpAddr::V6(std::net::Ipv6Addr::from_bits(
-                stream.read_u128().await?,
-            )),
-            stream.read_u16().await?,
-        ))
-    }
-
-    pub async fn domain_from_stream(
-        stream: &mut TcpStream,
-    ) -> Result<Self, Error> {
-        let domain_len = stream.read_u8().await?;
-        let mut domain_bytes = vec![0u8; domain_len as usize];
-        stream.read_exact(&mut domain_bytes).await?;
-        let domain = String::from_utf8(domain_bytes)?;
-        if domain.len() > 253 {
-            return Err(Error::InvalidDomain(domain));
-        };
-        let port = stream.read_u16().await?;
-        Ok(Self::Domain(domain, port))
-    }
-
-    pub async fn to_stream(&self, stream: &mut TcpStream) -> Result<(), Error> {
-        let port = match self {
-            Addr::Ip(IpAddr::V4(v4), port) => {
-                Self::write_v4(stream, v4).await?;
-                *port
-            }
-            Addr::Domain(domain, port) => {
-                Self::write_domain(stream, domain).await?;
-                *port
-            }
-            Addr::Ip(IpAddr::V6(v6), port) => {
-                Self::write_v6(stream, v6).await?;
-                *port
-            }
-            Addr::Null => {
-                Self::write_null(stream).await?;
-                0x00
-            }
-        };
-        stream.write_u16(port).await?;
-        Ok(())
+    /// The host without the port, as metrics and rate limits group it.
+    pub fn host(&self) -> Option<String> {
+        match self {
+            Addr::Ip(ip, _) => Some(ip.to_string()),
+            Addr::Domain(domain, _) => Some(domain.clone()),
+            Addr::Null => None,
+        }
     }
 
-    pub async fn resolve_dns(self) -> Result<Self, Error> {
+    /// Resolves the address to every IP it points at.
+    pub async fn lookup(
+        &self,
+        resolver: &dyn Resolver,
+    ) -> Result<Vec<IpAddr>, Error> {
         match self {
-            Addr::Domain(domain, port) => {
-                let addr = lookup_host((domain.as_str(), port))
-                    .await?
-                    .next()
-                    .ok_or(Error::InvalidDomain(domain.clone()))?;
-                Ok(Self::from_ip_addr(addr.ip(), port))
+            Addr::Ip(ip, _) => Ok(vec![*ip]),
+            Addr::Domain(domain, _) => Ok(resolver.resolve(domain).await?.ips),
+            Addr::Null => {
+                Err(Error::Internal("tried to resolve a null address."))
             }
-            _ => Ok(self),
         }
     }
-
-    async fn write_v6(
-        stream: &mut TcpStream,
-        v6: &std::net::Ipv6Addr,
-    ) -> Result<(), Error> {
-        stream.write_u8(0x04).await?;
-        stream.write_u128(v6.to_bits()).await?;
-        Ok(())
-    }
-
-    async fn write_domain(
-        stream: &mut TcpStream,
-        domain: &String,
-    ) -> Result<(), Error> {
-        stream.write_u8(0x03).await?;
-        stream
-            .write_u8(
-                domain
-                    .len()
-                    .try_into()
-                    .expect("domain length to be no more than 253"),
-            )
-            .await?;
-        stream.write_all(domain.as_bytes()).await?;
-        Ok(())
-    }
-
-    async fn write_v4(
-        stream: &mut TcpStream,
-        v4: &Ipv4Addr,
-    ) -> Result<(), Error> {
-        stream.write_u8(0x01).await?;
-        stream.write_u32(v4.to_bits()).await?;
-        Ok(())
-    }
-
-    async fn write_null(stream: &mut TcpStream) -> Result<(), Error> {
-        stream.write_u8(0x00).await?;
-        Ok(())
-    }
 }
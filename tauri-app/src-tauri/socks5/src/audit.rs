@@ -0,0 +1,317 @@
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use serde::{Serialize, Serializer};
+use tokio::time::Instant;
+
+use crate::{Addr, Cmd, auth::Identity, filter::Refused};
+
+/// Whether a connection attempt went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Allowed,
+    Denied,
+}
+
+/// Where in its life a connection was when it was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Relayed from now on, the traffic is still zero.
+    Opened,
+    /// Over, or never started for denied ones.
+    Closed,
+}
+
+/// One connection attempt. Allowed ones are recorded twice, once they are
+/// relayed and again with their traffic once they close. Denied ones are
+/// recorded once, straight away.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// When the request was read, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub peer: SocketAddr,
+    /// `None` like the identity and target when the client failed its
+    /// handshake.
+    #[serde(serialize_with = "display")]
+    pub cmd: Option<Cmd>,
+    #[serde(serialize_with = "display")]
+    pub identity: Option<Identity>,
+    /// The destination as the client asked for it.
+    #[serde(serialize_with = "display")]
+    pub target: Option<Addr>,
+    /// The address the proxy connected to, which is the upstream proxy's for
+    /// chained connections.
+    pub resolved: Option<IpAddr>,
+    pub decision: Verdict,
+    pub phase: Phase,
+    /// [`Error::reason`](crate::error::Error::reason) of a denial.
+    pub reason: Option<&'static str>,
+    /// The filter which denied the connection, or the last one which did
+    /// more than allow it.
+    pub filter: Option<String>,
+    /// What the client was answered with in the protocol it spoke, a SOCKS
+    /// reply code or an HTTP status.
+    pub reply: Option<u16>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+fn display<T: Display, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Receives every [`AuditRecord`], see [`Server::add_audit_sink`].
+///
+/// Plain closures taking a record are sinks already.
+///
+/// [`Server::add_audit_sink`]: crate::Server::add_audit_sink
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// Appends records to a file as JSON lines. Once the file would grow past
+/// `max_bytes` it is moved to `<path>.1`, `<path>.1` to `<path>.2` and so on,
+/// keeping up to `keep` old files.
+pub struct AuditFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    /// The open file and how much has been written to it.
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditFile {
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Shifts the old files along and starts a new one.
+    fn rotate(&self) -> io::Result<File> {
+        fn ignore_missing(res: io::Result<()>) -> io::Result<()> {
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            }
+        }
+        match self.keep {
+            0 => ignore_missing(fs::remove_file(&self.path))?,
+            keep => {
+                for n in (1..keep).rev() {
+                    let from = self.rotated(n);
+                    ignore_missing(fs::rename(from, self.rotated(n + 1)))?;
+                }
+                ignore_missing(fs::rename(&self.path, self.rotated(1)))?;
+            }
+        }
+        File::create(&self.path)
+    }
+
+    fn write(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().expect("audit lock to not be poisoned");
+        let (current, written) = &mut *file;
+        if *written > 0 && *written + line.len() as u64 > self.max_bytes {
+            *current = self.rotate()?;
+            *written = 0;
+        }
+        current.write_all(&line)?;
+        *written += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl AuditSink for AuditFile {
+    fn record(&self, record: &AuditRecord) {
+        if let Err(e) = self.write(record) {
+            error!("could not write to {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// A record in the making, sent to the sinks once the connection is over.
+#[derive(Clone)]
+pub(crate) struct Audit {
+    record: AuditRecord,
+    started: Instant,
+    sinks: Arc<[Arc<dyn AuditSink>]>,
+}
+
+impl Audit {
+    pub fn new(sinks: Arc<[Arc<dyn AuditSink>]>, peer: SocketAddr) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            record: AuditRecord {
+                timestamp_ms,
+                peer,
+                cmd: None,
+                identity: None,
+                target: None,
+                resolved: None,
+                decision: Verdict::Denied,
+                phase: Phase::Closed,
+                reason: None,
+                filter: None,
+                reply: None,
+                bytes_up: 0,
+                bytes_down: 0,
+                duration_ms: 0,
+            },
+            started: Instant::now(),
+            sinks,
+        }
+    }
+
+    pub fn request(
+        mut self,
+        cmd: Cmd,
+        identity: &Identity,
+        target: &Addr,
+    ) -> Self {
+        self.record.cmd = Some(cmd);
+        self.record.identity = Some(identity.clone());
+        self.record.target = Some(target.clone());
+        self
+    }
+
+    /// Marks the connection as let through by the policy, `filter` being
+    /// the one which had a say.
+    pub fn allowed(mut self, filter: Option<String>, reply: u16) -> Self {
+        self.record.decision = Verdict::Allowed;
+        self.record.filter = filter;
+        self.record.reply = Some(reply);
+        self
+    }
+
+    pub fn set_resolved(&mut self, ip: IpAddr) {
+        self.record.resolved = Some(ip);
+    }
+
+    /// Records a refusal right away.
+    pub fn denied(mut self, refused: &Refused, reply: Option<u16>) {
+        self.record.decision = Verdict::Denied;
+        self.record.reason = Some(refused.err.reason());
+        self.record.filter = refused.by.clone();
+        self.record.reply = reply;
+        self.finish(0, 0);
+    }
+
+    /// Records the connection as relayed, leaving this one to be finished
+    /// once it closes.
+    pub fn opened(&self) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let mut opened = self.clone();
+        opened.record.phase = Phase::Opened;
+        opened.send();
+    }
+
+    pub fn finish(mut self, bytes_up: u64, bytes_down: u64) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        self.record.phase = Phase::Closed;
+        self.record.bytes_up = bytes_up;
+        self.record.bytes_down = bytes_down;
+        self.send();
+    }
+
+    fn send(&mut self) {
+        self.record.duration_ms = self.started.elapsed().as_millis() as u64;
+        for sink in self.sinks.iter() {
+            sink.record(&self.record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::{Audit, AuditFile, AuditSink};
+    use crate::{Addr, Cmd, Identity, error::Error, filter::Refused};
+
+    #[test]
+    fn files_rotate_by_size() {
+        let dir = std::env::temp_dir()
+            .join(format!("socks5-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let file: Arc<dyn AuditSink> =
+            Arc::new(AuditFile::open(&path, 600, 2).unwrap());
+
+        let peer = "127.0.0.1:5000".parse().unwrap();
+        let target = Addr::Domain("tracker.test".into(), 443);
+        let denied = Refused::by(Error::BreaksRuleset, "blocklist");
+        for _ in 0..7 {
+            Audit::new(Arc::new([file.clone()]), peer)
+                .request(Cmd::Connect, &Identity::Anonymous, &target)
+                .denied(&denied, Some(0x02));
+        }
+
+        let line = fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["target"], "tracker.test:443");
+        assert_eq!(record["identity"], "<anonymous>");
+        assert_eq!(record["decision"], "denied");
+        assert_eq!(record["reason"], "ruleset");
+        assert_eq!(record["filter"], "blocklist");
+        assert_eq!(record["reply"], 2);
+        // two records fit a file, the oldest ones fell off the end
+        for old in ["audit.jsonl.1", "audit.jsonl.2"] {
+            let old = fs::read_to_string(dir.join(old)).unwrap();
+            assert_eq!(old.lines().count(), 2);
+        }
+        assert!(!dir.join("audit.jsonl.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt::Display;
+
+use log::trace;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+
+use super::Error;
+use crate::codec::{
+    self, AuthStatus, Greeting, MethodSelection, NO_ACCEPTABLE_METHODS,
+    NO_AUTH, USERNAME_PASSWORD, UserPass,
+};
+
+/// Checks a username and password sent by a client, see RFC 1929.
+pub type Authenticator = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// Who opened a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// The client did not authenticate, only possible when the server has no
+    /// authenticator set.
+    Anonymous,
+    /// The client authenticated with this username.
+    User(String),
+}
+
+impl Identity {
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Identity::Anonymous => None,
+            Identity::User(username) => Some(username),
+        }
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::Anonymous => f.write_str("<anonymous>"),
+            Identity::User(username) => f.write_str(username),
+        }
+    }
+}
+
+/// Reads the client's method selection message and authenticates it.
+///
+/// Without an authenticator only NO AUTHENTICATION REQUIRED is accepted,
+/// with one only USERNAME/PASSWORD is.
+pub(crate) async fn negotiate<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    authenticator: Option<&Authenticator>,
+) -> Result<Identity, Error> {
+    let Greeting { methods } = codec::read(stream).await?;
+    trace!("{} auths supported: {:02X?}", methods.len(), methods);
+
+    let wanted = match authenticator {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTH,
+    };
+    if !methods.contains(&wanted) {
+        let method = NO_ACCEPTABLE_METHODS;
+        codec::write(stream, &MethodSelection { method }).await?;
+        // returning drops the stream, shuts it down
+        return Err(Error::InvalidAuth);
+    }
+    codec::write(stream, &MethodSelection { method: wanted }).await?;
+
+    match authenticator {
+        Some(authenticator) => username_password(stream, authenticator).await,
+        None => Ok(Identity::Anonymous),
+    }
+}
+
+/// Runs the RFC 1929 sub-negotiation.
+async fn username_password<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    authenticator: &Authenticator,
+) -> Result<Identity, Error> {
+    let failed = AuthStatus { success: false };
+    let UserPass { username, password } = match codec::read(stream).await {
+        Ok(v) => v,
+        Err(Error::AuthFailed) => {
+            codec::write(stream, &failed).await?;
+            return Err(Error::AuthFailed);
+        }
+        Err(e) => return Err(e),
+    };
+    if !authenticator(&username, &password) {
+        trace!("rejected credentials for {username}");
+        codec::write(stream, &failed).await?;
+        return Err(Error::AuthFailed);
+    }
+    codec::write(stream, &AuthStatus { success: true }).await?;
+    Ok(Identity::User(username))
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::{Addr, Error, auth::Identity};
+
+/// Caps on the connections open at the same time, unset ones don't limit.
+///
+/// Only connections being relayed count. Clients still in their handshake
+/// are capped separately: once `total` of them are, the server stops
+/// accepting and leaves the rest in the listener's backlog.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub total: Option<usize>,
+    /// Applies to each identity separately, every anonymous client counts as
+    /// the same identity.
+    pub per_identity: Option<usize>,
+    /// Applies to each destination separately, keyed by the host the client
+    /// asked for.
+    pub per_host: Option<usize>,
+    pub when_full: WhenFull,
+}
+
+/// What happens to a request which would go over a cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Refuse it straight away with a general failure.
+    #[default]
+    Refuse,
+    /// Let up to `max_waiting` requests wait up to `timeout` for other
+    /// connections to close. Requests beyond that are refused.
+    Wait {
+        max_waiting: usize,
+        timeout: Duration,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    identities: HashMap<Identity, usize>,
+    hosts: HashMap<String, usize>,
+}
+
+/// Counts the open connections of a server and its clones against its
+/// [`ConnectionLimits`].
+#[derive(Debug)]
+pub(crate) struct ConnectionCaps {
+    limits: ConnectionLimits,
+    open: Mutex<Open>,
+    closed: Notify,
+    queue: Semaphore,
+    handshakes: Option<Arc<Semaphore>>,
+}
+
+impl Default for ConnectionCaps {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default())
+    }
+}
+
+impl ConnectionCaps {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        let max_waiting = match limits.when_full {
+            WhenFull::Refuse => 0,
+            WhenFull::Wait { max_waiting, .. } => max_waiting,
+        };
+        Self {
+            limits,
+            open: Mutex::default(),
+            closed: Notify::new(),
+            queue: Semaphore::new(max_waiting),
+            handshakes: limits.total.map(|n| Arc::new(Semaphore::new(n))),
+        }
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        self.limits
+    }
+
+    /// Waits until another client may start its handshake. The permit is
+    /// meant to be dropped once the handshake is done.
+    pub async fn handshake_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let handshakes = self.handshakes.clone()?;
+        handshakes.acquire_owned().await.ok()
+    }
+
+    /// Takes a slot for a connection from `identity` to `host`, waiting for
+    /// one if the limits say so. Refused with
+    /// [`Error::TooManyConnections`].
+    pub async fn acquire(
+        self: &Arc<Self>,
+        identity: &Identity,
+        host: Option<&Addr>,
+    ) -> Result<Slot, Error> {
+        let host = host.and_then(Addr::host);
+        if let Some(slot) = self.try_acquire(identity, host.as_ref()) {
+            return Ok(slot);
+        }
+        let WhenFull::Wait { timeout: wait, .. } = self.limits.when_full else {
+            return Err(Error::TooManyConnections);
+        };
+        let Ok(_queued) = self.queue.try_acquire() else {
+            return Err(Error::TooManyConnections);
+        };
+        let slot = async {
+            loop {
+                // registered before trying so a close in between isn't missed
+                let closed = self.closed.notified();
+                tokio::pin!(closed);
+                closed.as_mut().enable();
+                if let Some(slot) = self.try_acquire(identity, host.as_ref()) {
+                    return slot;
+                }
+                closed.await;
+            }
+        };
+        timeout(wait, slot)
+            .await
+            .map_err(|_| Error::TooManyConnections)
+    }
+
+    fn try_acquire(
+        self: &Arc<Self>,
+        identity: &Identity,
+        host: Option<&String>,
+    ) -> Option<Slot> {
+        fn below<K: Eq + Hash>(
+            counts: &HashMap<K, usize>,
+            key: Option<&K>,
+            cap: Option<usize>,
+        ) -> bool {
+            let count = key.and_then(|k| counts.get(k)).copied();
+            cap.is_none_or(|cap| count.unwrap_or(0) < cap)
+        }
+        let limits = &self.limits;
+        let mut open = self.open.lock().expect("caps lock to not be poisoned");
+        let free = limits.total.is_none_or(|cap| open.total < cap)
+            && below(&open.identities, Some(identity), limits.per_identity)
+            && below(&open.hosts, host, limits.per_host);
+        if !free {
+            return None;
+        }
+        open.total += 1;
+        *open.identities.entry(identity.clone()).or_default() += 1;
+        if let Some(host) = host {
+            *open.hosts.entry(host.clone()).or_default() += 1;
+        }
+        Some(Slot {
+            caps: self.clone(),
+            identity: identity.clone(),
+            host: host.cloned(),
+        })
+    }
+}
+
+/// Counts a connection as open until dropped.
+#[derive(Debug)]
+pub(crate) struct Slot {
+    caps: Arc<ConnectionCaps>,
+    identity: Identity,
+    host: Option<String>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        fn release<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: &K) {
+            if let Some(count) = counts.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+        let mut open =
+            self.caps.open.lock().expect("caps lock to not be poisoned");
+        open.total -= 1;
+        release(&mut open.identities, &self.identity);
+        if let Some(host) = &self.host {
+            release(&mut open.hosts, host);
+        }
+        drop(open);
+        self.caps.closed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{ConnectionCaps, ConnectionLimits, WhenFull};
+    use crate::{Addr, Identity, error::Error};
+
+    #[tokio::test]
+    async fn refuses_over_the_caps() {
+        let caps = Arc::new(ConnectionCaps::new(ConnectionLimits {
+            total: Some(3),
+            per_identity: Some(2),
+            per_host: Some(1),
+            when_full: WhenFull::Refuse,
+        }));
+        let user = Identity::User("doc-1".into());
+        let a = Addr::Domain("a.test".into(), 443);
+        let b = Addr::Domain("b.test".into(), 443);
+
+        let first = caps.acquire(&user, Some(&a)).await.unwrap();
+        assert!(matches!(
+            caps.acquire(&Identity::Anonymous, Some(&a)).await,
+            Err(Error::TooManyConnections)
+        ));
+        let _second = caps.acquire(&user, Some(&b)).await.unwrap();
+        assert!(caps.acquire(&user, None).await.is_err());
+        let _third = caps.acquire(&Identity::Anonymous, None).await.unwrap();
+        assert!(caps.acquire(&Identity::Anonymous, None).await.is_err());
+
+        drop(first);
+        caps.acquire(&Identity::Anonymous, Some(&a)).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_in_a_bounded_queue() {
+        let caps = Arc::new(ConnectionCaps::new(ConnectionLimits {
+            total: Some(1),
+            when_full: WhenFull::Wait {
+                max_waiting: 1,
+                timeout: Duration::from_secs(5),
+            },
+            ..ConnectionLimits::default()
+        }));
+        let anon = Identity::Anonymous;
+        let open = caps.acquire(&anon, None).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let caps = caps.clone();
+            async move { caps.acquire(&Identity::Anonymous, None).await }
+        });
+        tokio::task::yield_now().await;
+        // the queue is full
+        assert!(caps.acquire(&anon, None).await.is_err());
+        drop(open);
+        let slot = waiting.await.unwrap().unwrap();
+
+        // nothing closes within the timeout
+        assert!(matches!(
+            caps.acquire(&anon, None).await,
+            Err(Error::TooManyConnections)
+        ));
+        drop(slot);
+    }
+}
@@ -0,0 +1,225 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite},
+    net::UdpSocket,
+};
+
+use crate::{
+    Addr, Cmd, Error,
+    codec::{
+        self, AuthStatus, Greeting, MethodSelection, NO_ACCEPTABLE_METHODS,
+        NO_AUTH, Request, USERNAME_PASSWORD, UdpHeader, UserPass, VERSION,
+    },
+    upstream::Credentials,
+};
+
+/// Largest payload a UDP datagram can carry.
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Talks to a SOCKS5 proxy over a stream which is already connected to it.
+///
+/// Every command runs the greeting first, offering username/password auth
+/// along with none when there are credentials. Replies other than success
+/// come back as the [`Error`] for their code, see [`Error::from_reply`].
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    credentials: Option<Credentials>,
+}
+
+impl Client {
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        Self { credentials }
+    }
+
+    /// Asks the proxy to connect to `target`. Returns the stream, which now
+    /// leads to `target`, and the address the proxy connected from.
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        target: Addr,
+    ) -> Result<(S, Addr), Error> {
+        let bound = self.request(&mut stream, Cmd::Connect, target).await?;
+        Ok((stream, bound))
+    }
+
+    /// Asks the proxy to listen for a connection from `peer`, zeros if it
+    /// isn't known yet. See [`Bind::accept`] for the connection itself.
+    pub async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        peer: Addr,
+    ) -> Result<Bind<S>, Error> {
+        let addr = self.request(&mut stream, Cmd::Bind, peer).await?;
+        Ok(Bind { stream, addr })
+    }
+
+    /// Asks the proxy for a UDP relay. The association lasts as long as
+    /// `stream` stays open.
+    pub async fn udp_associate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+    ) -> Result<UdpAssociation<S>, Error> {
+        // we don't know the port we'll send from until the relay is known
+        let unknown = Addr::from_ip_addr(Ipv4Addr::UNSPECIFIED.into(), 0);
+        let relay = self.request(&mut stream, Cmd::UdpAssociate, unknown);
+        let relay = match relay.await? {
+            Addr::Ip(ip, port) => SocketAddr::new(ip, port),
+            _ => return Err(Error::AddressTypeNotSupported),
+        };
+        let local = match relay.ip() {
+            ip if ip.is_loopback() => ip,
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(relay).await?;
+        Ok(UdpAssociation {
+            control: stream,
+            socket,
+        })
+    }
+
+    /// Runs the greeting and whichever auth the proxy picked.
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(), Error> {
+        let methods = match self.credentials {
+            Some(_) => vec![NO_AUTH, USERNAME_PASSWORD],
+            None => vec![NO_AUTH],
+        };
+        codec::write(stream, &Greeting { methods }).await?;
+        let MethodSelection { method } = codec::read_exact(stream).await?;
+        match (method, &self.credentials) {
+            (NO_AUTH, _) => Ok(()),
+            (USERNAME_PASSWORD, Some(credentials)) => {
+                let Credentials { username, password } = credentials;
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(Error::Internal("credentials too long"));
+                }
+                let auth = UserPass {
+                    username: username.clone(),
+                    password: password.clone(),
+                };
+                codec::write(stream, &auth).await?;
+                let status: AuthStatus = codec::read_exact(stream).await?;
+                match status.success {
+                    true => Ok(()),
+                    false => Err(Error::AuthFailed),
+                }
+            }
+            (NO_ACCEPTABLE_METHODS, _) => Err(Error::InvalidAuth),
+            _ => Err(Error::Malformed("proxy picked a method not offered")),
+        }
+    }
+
+    /// Authenticates and sends a request, returning the address of the
+    /// successful reply.
+    async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        cmd: Cmd,
+        addr: Addr,
+    ) -> Result<Addr, Error> {
+        let addr = match addr {
+            Addr::Domain(domain, port) => {
+                Addr::try_from_domain(domain, port).await?
+            }
+            addr => addr,
+        };
+        self.authenticate(stream).await?;
+        codec::write(stream, &Request { cmd, addr }).await?;
+        read_reply(stream, cmd).await
+    }
+}
+
+/// Reads a reply, the address of a failed one isn't read since some proxies
+/// send garbage there and the connection is over anyway.
+async fn read_reply<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cmd: Cmd,
+) -> Result<Addr, Error> {
+    let mut head = [0u8; 3];
+    stream.read_exact(&mut head).await?;
+    let [version, code, _rsv] = head;
+    if version != VERSION {
+        return Err(Error::VersionMismatch);
+    }
+    if let Some(err) = Error::from_reply(code, cmd) {
+        return Err(err);
+    }
+    codec::read_exact::<Addr, _>(stream).await
+}
+
+/// A BIND the proxy is listening for.
+#[derive(Debug)]
+pub struct Bind<S> {
+    stream: S,
+    addr: Addr,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Bind<S> {
+    /// Where the proxy listens, for the peer to connect to.
+    pub fn addr(&self) -> &Addr {
+        &self.addr
+    }
+
+    /// Waits for the peer to connect. Returns the stream, which now leads
+    /// to the peer, and the peer's address.
+    pub async fn accept(mut self) -> Result<(S, Addr), Error> {
+        let peer = read_reply(&mut self.stream, Cmd::Bind).await?;
+        Ok((self.stream, peer))
+    }
+}
+
+/// A UDP relay the proxy set up, datagrams are sent to and received from it
+/// with their SOCKS5 header taken care of.
+#[derive(Debug)]
+pub struct UdpAssociation<S> {
+    control: S,
+    socket: UdpSocket,
+}
+
+impl<S> UdpAssociation<S> {
+    /// Where the proxy relays from.
+    pub fn relay_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.peer_addr()?)
+    }
+
+    /// Sends `payload` to `target` through the relay.
+    pub async fn send_to(
+        &self,
+        payload: &[u8],
+        target: Addr,
+    ) -> Result<(), Error> {
+        let datagram = UdpHeader::new(target).encapsulate(payload);
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    /// Receives the next datagram into `buf`, returning its length and
+    /// where it came from. Fragmented datagrams are dropped.
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<(usize, Addr), Error> {
+        let mut datagram = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let len = self.socket.recv(&mut datagram).await?;
+            let (header, payload) = UdpHeader::parse(&datagram[..len])?;
+            if header.frag != 0x00 {
+                continue;
+            }
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            return Ok((len, header.addr));
+        }
+    }
+
+    /// Ends the association by handing back the control stream, closing it
+    /// tells the proxy.
+    pub fn into_control(self) -> S {
+        self.control
+    }
+}
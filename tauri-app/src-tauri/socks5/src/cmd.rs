@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use super::error::Error;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Cmd {
     Connect,
     Bind,
@@ -17,7 +17,17 @@ impl TryFrom<u8> for Cmd {
             0x01 => Ok(Cmd::Connect),
             0x02 => Ok(Cmd::Bind),
             0x03 => Ok(Cmd::UdpAssociate),
-            _ => Err(Error::CmdNotSupported(Cmd::Connect)),
+            cmd => Err(Error::CmdNotSupported(cmd)),
+        }
+    }
+}
+
+impl From<Cmd> for u8 {
+    fn from(cmd: Cmd) -> Self {
+        match cmd {
+            Cmd::Connect => 0x01,
+            Cmd::Bind => 0x02,
+            Cmd::UdpAssociate => 0x03,
         }
     }
 }
@@ -0,0 +1,587 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt as _, AsyncRead, AsyncReadExt as _,
+    AsyncWrite, AsyncWriteExt as _,
+};
+
+use crate::{Addr, Cmd, Error};
+
+/// The SOCKS version every message but the RFC 1929 ones starts with.
+pub const VERSION: u8 = 0x05;
+/// The version of the RFC 1929 username/password sub-negotiation.
+pub const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+pub const NO_AUTH: u8 = 0x00;
+pub const USERNAME_PASSWORD: u8 = 0x02;
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+/// What decoding the start of a buffer came up with.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded<T> {
+    /// A whole message and how many bytes of the buffer it took up.
+    Done(T, usize),
+    /// The buffer ends early, at least this many more bytes are needed. Never
+    /// more than the message is missing, so reading exactly that much can't
+    /// eat into whatever follows it.
+    NeedMore(usize),
+}
+
+/// A message which can be read off the start of a byte buffer.
+pub trait Decode: Sized {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error>;
+}
+
+/// A message which can be appended to a byte buffer.
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// The client's method selection message, it opens every connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Greeting {
+    pub methods: Vec<u8>,
+}
+
+/// The method the server picked out of a [`Greeting`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodSelection {
+    pub method: u8,
+}
+
+/// The RFC 1929 username/password request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserPass {
+    pub username: String,
+    pub password: String,
+}
+
+/// The answer to a [`UserPass`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthStatus {
+    pub success: bool,
+}
+
+/// What the client wants done once it is through authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub cmd: Cmd,
+    pub addr: Addr,
+}
+
+/// The server's answer to a [`Request`], sent twice for a BIND.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// `0x00` for success, otherwise see [`Error::to_u8`].
+    pub code: u8,
+    pub addr: Addr,
+}
+
+/// The header in front of every datagram relayed through a UDP ASSOCIATE, see
+/// RFC 1928 section 7.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub addr: Addr,
+}
+
+impl UdpHeader {
+    pub fn new(addr: Addr) -> Self {
+        Self { frag: 0x00, addr }
+    }
+
+    /// Splits a datagram into its header and payload.
+    pub fn parse(datagram: &[u8]) -> Result<(Self, &[u8]), Error> {
+        match Self::decode(datagram)? {
+            Decoded::Done(header, len) => Ok((header, &datagram[len..])),
+            Decoded::NeedMore(_) => {
+                Err(Error::Malformed("UDP datagram shorter than its header"))
+            }
+        }
+    }
+
+    /// Builds a datagram out of this header followed by `payload`.
+    pub fn encapsulate(&self, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(payload.len() + 22);
+        self.encode(&mut datagram);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+}
+
+/// Why a message couldn't be read off a buffer.
+enum Fail {
+    Short(usize),
+    Invalid(Error),
+}
+
+impl From<Error> for Fail {
+    fn from(err: Error) -> Self {
+        Fail::Invalid(err)
+    }
+}
+
+/// Takes fields off the front of a buffer, noting how much was missing when
+/// one runs past its end.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Runs `read` over `buf` and reports how far it got.
+    fn decode<T>(
+        buf: &'a [u8],
+        read: impl FnOnce(&mut Self) -> Result<T, Fail>,
+    ) -> Result<Decoded<T>, Error> {
+        let mut reader = Self { buf, pos: 0 };
+        match read(&mut reader) {
+            Ok(msg) => Ok(Decoded::Done(msg, reader.pos)),
+            Err(Fail::Short(missing)) => Ok(Decoded::NeedMore(missing)),
+            Err(Fail::Invalid(err)) => Err(err),
+        }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Fail> {
+        let bytes = self.take(N)?;
+        Ok(bytes.try_into().expect("take to return N bytes"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Fail> {
+        let rest = &self.buf[self.pos..];
+        let bytes = rest
+            .get(..len)
+            .ok_or_else(|| Fail::Short(len - rest.len()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Fail> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Fail> {
+        Ok(u16::from_be_bytes(self.bytes()?))
+    }
+
+    /// A field preceded by its length in one byte.
+    fn field(&mut self) -> Result<&'a [u8], Fail> {
+        let len = self.u8()?;
+        self.take(len.into())
+    }
+
+    fn version(&mut self, version: u8, err: Error) -> Result<(), Fail> {
+        match self.u8()? == version {
+            true => Ok(()),
+            false => Err(err.into()),
+        }
+    }
+
+    fn addr(&mut self) -> Result<Addr, Fail> {
+        let addr = match self.u8()? {
+            0x01 => {
+                let ip = Ipv4Addr::from(self.bytes::<4>()?);
+                Addr::Ip(IpAddr::V4(ip), self.u16()?)
+            }
+            0x03 => {
+                let domain = String::from_utf8(self.field()?.to_vec())
+                    .map_err(Error::from)?;
+                if domain.len() > 253 {
+                    return Err(Error::InvalidDomain(domain).into());
+                }
+                Addr::Domain(domain, self.u16()?)
+            }
+            0x04 => {
+                let ip = Ipv6Addr::from(self.bytes::<16>()?);
+                Addr::Ip(IpAddr::V6(ip), self.u16()?)
+            }
+            _ => return Err(Error::AddressTypeNotSupported.into()),
+        };
+        Ok(addr)
+    }
+}
+
+impl Decode for Addr {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, Reader::addr)
+    }
+}
+
+impl Encode for Addr {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Addr::Ip(IpAddr::V4(v4), _) => {
+                out.push(0x01);
+                out.extend(v4.octets());
+            }
+            Addr::Domain(domain, _) => {
+                // see Addr::try_from_domain, which every domain is built with
+                let len = u8::try_from(domain.len())
+                    .expect("domain length to be no more than 253");
+                out.extend([0x03, len]);
+                out.extend(domain.as_bytes());
+            }
+            Addr::Ip(IpAddr::V6(v6), _) => {
+                out.push(0x04);
+                out.extend(v6.octets());
+            }
+            Addr::Null => out.push(0x00),
+        }
+        out.extend(self.port().to_be_bytes());
+    }
+}
+
+impl Decode for Greeting {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(VERSION, Error::VersionMismatch)?;
+            let methods = r.field()?.to_vec();
+            Ok(Self { methods })
+        })
+    }
+}
+
+impl Encode for Greeting {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let len =
+            u8::try_from(self.methods.len()).expect("at most 255 methods");
+        out.extend([VERSION, len]);
+        out.extend(&self.methods);
+    }
+}
+
+impl Decode for MethodSelection {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(VERSION, Error::VersionMismatch)?;
+            Ok(Self { method: r.u8()? })
+        })
+    }
+}
+
+impl Encode for MethodSelection {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend([VERSION, self.method]);
+    }
+}
+
+impl Decode for UserPass {
+    /// Anything but a well formed request with UTF-8 credentials fails with
+    /// [`Error::AuthFailed`].
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(USERNAME_PASSWORD_VERSION, Error::AuthFailed)?;
+            let username = r.field()?.to_vec();
+            let password = r.field()?.to_vec();
+            match (String::from_utf8(username), String::from_utf8(password)) {
+                (Ok(username), Ok(password)) => Ok(Self { username, password }),
+                _ => Err(Error::AuthFailed.into()),
+            }
+        })
+    }
+}
+
+impl Encode for UserPass {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(USERNAME_PASSWORD_VERSION);
+        for field in [&self.username, &self.password] {
+            let len = u8::try_from(field.len())
+                .expect("credentials to be no longer than 255 bytes");
+            out.push(len);
+            out.extend(field.as_bytes());
+        }
+    }
+}
+
+impl Decode for AuthStatus {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(USERNAME_PASSWORD_VERSION, Error::AuthFailed)?;
+            Ok(Self {
+                success: r.u8()? == 0x00,
+            })
+        })
+    }
+}
+
+impl Encode for AuthStatus {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let status = match self.success {
+            true => 0x00,
+            false => 0x01,
+        };
+        out.extend([USERNAME_PASSWORD_VERSION, status]);
+    }
+}
+
+impl Decode for Request {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(VERSION, Error::VersionMismatch)?;
+            let cmd = Cmd::try_from(r.u8()?)?;
+            let _rsv = r.u8()?;
+            Ok(Self {
+                cmd,
+                addr: r.addr()?,
+            })
+        })
+    }
+}
+
+impl Encode for Request {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend([VERSION, self.cmd.into(), 0x00]);
+        self.addr.encode(out);
+    }
+}
+
+impl Decode for Reply {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            r.version(VERSION, Error::VersionMismatch)?;
+            let code = r.u8()?;
+            let _rsv = r.u8()?;
+            Ok(Self {
+                code,
+                addr: r.addr()?,
+            })
+        })
+    }
+}
+
+impl Encode for Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend([VERSION, self.code, 0x00]);
+        self.addr.encode(out);
+    }
+}
+
+impl Decode for UdpHeader {
+    fn decode(buf: &[u8]) -> Result<Decoded<Self>, Error> {
+        Reader::decode(buf, |r| {
+            let [_rsv0, _rsv1, frag] = r.bytes()?;
+            Ok(Self {
+                frag,
+                addr: r.addr()?,
+            })
+        })
+    }
+}
+
+impl Encode for UdpHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend([0x00, 0x00, self.frag]);
+        self.addr.encode(out);
+    }
+}
+
+/// Reads one message off a buffered stream, never consuming past its end.
+///
+/// Whatever the stream already buffered is decoded first, so a message the
+/// client sent in one go takes one read however many fields it has.
+pub(crate) async fn read<T: Decode, R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+) -> Result<T, Error> {
+    let mut buf = Vec::new();
+    loop {
+        match T::decode(&buf)? {
+            Decoded::Done(msg, _) => return Ok(msg),
+            Decoded::NeedMore(missing) => {
+                let available = stream.fill_buf().await?;
+                if available.is_empty() {
+                    let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    return Err(eof.into());
+                }
+                // what's missing never reaches into the next message
+                let taken = missing.min(available.len());
+                buf.extend_from_slice(&available[..taken]);
+                stream.consume(taken);
+            }
+        }
+    }
+}
+
+/// Reads one message off an unbuffered stream a field at a time, never past
+/// its end. Clients use it since whatever follows a reply isn't ours to
+/// buffer.
+pub(crate) async fn read_exact<T: Decode, R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<T, Error> {
+    let mut buf = Vec::new();
+    loop {
+        match T::decode(&buf)? {
+            Decoded::Done(msg, _) => return Ok(msg),
+            Decoded::NeedMore(missing) => {
+                let read = buf.len();
+                buf.resize(read + missing, 0);
+                stream.read_exact(&mut buf[read..]).await?;
+            }
+        }
+    }
+}
+
+/// Writes one message in a single go.
+pub(crate) async fn write<T: Encode, W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    msg: &T,
+) -> Result<(), Error> {
+    Ok(stream.write_all(&msg.to_vec()).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    };
+
+    use super::{
+        AuthStatus, Decode, Decoded, Encode, Greeting, MethodSelection, Reply,
+        Request, UdpHeader, UserPass, read, read_exact,
+    };
+    use crate::{Addr, Cmd, error::Error};
+
+    fn addrs() -> Vec<Addr> {
+        vec![
+            Addr::Ip(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)), 5353),
+            Addr::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            Addr::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST), 443),
+            Addr::Ip("2001:db8::1".parse().unwrap(), u16::MAX),
+            Addr::Domain("example.com".into(), 80),
+            Addr::Domain(String::new(), 1),
+            Addr::Domain("a".repeat(253), 8080),
+        ]
+    }
+
+    /// Decodes `msg` whole, from every prefix and fed a byte at a time, with
+    /// trailing bytes which must be left alone.
+    async fn round_trips<T: Decode + Encode + PartialEq + Debug>(msg: T) {
+        let encoded = msg.to_vec();
+        let mut trailing = encoded.clone();
+        trailing.extend(b"payload");
+        match T::decode(&trailing).unwrap() {
+            Decoded::Done(decoded, len) => {
+                assert_eq!(decoded, msg);
+                assert_eq!(len, encoded.len(), "{msg:?}");
+            }
+            Decoded::NeedMore(_) => panic!("{msg:?} decoded as incomplete"),
+        }
+        for len in 0..encoded.len() {
+            match T::decode(&encoded[..len]).unwrap() {
+                Decoded::NeedMore(missing) => {
+                    assert!(missing > 0 && len + missing <= encoded.len());
+                }
+                Decoded::Done(..) => panic!("{msg:?} decoded from {len}"),
+            }
+        }
+        let mut stream = trailing.as_slice();
+        let decoded = read::<T, _>(&mut stream).await.unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(stream, b"payload");
+        let mut stream = trailing.as_slice();
+        let decoded = read_exact::<T, _>(&mut stream).await.unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(stream, b"payload");
+    }
+
+    #[tokio::test]
+    async fn every_message_round_trips() {
+        for methods in [vec![], vec![0x00], vec![0x00, 0x02], vec![7; 255]] {
+            round_trips(Greeting { methods }).await;
+        }
+        for method in [0x00, 0x02, 0xFF] {
+            round_trips(MethodSelection { method }).await;
+        }
+        for (username, password) in [("", ""), ("doc-1", "token")] {
+            round_trips(UserPass {
+                username: username.into(),
+                password: password.into(),
+            })
+            .await;
+        }
+        round_trips(UserPass {
+            username: "u".repeat(255),
+            password: "p".repeat(255),
+        })
+        .await;
+        for success in [true, false] {
+            round_trips(AuthStatus { success }).await;
+        }
+        for addr in addrs() {
+            round_trips(addr.clone()).await;
+            for cmd in [Cmd::Connect, Cmd::Bind, Cmd::UdpAssociate] {
+                let addr = addr.clone();
+                round_trips(Request { cmd, addr }).await;
+            }
+            for code in 0x00..=0x08 {
+                let addr = addr.clone();
+                round_trips(Reply { code, addr }).await;
+            }
+            for frag in [0x00, 0x01, 0x83] {
+                let addr = addr.clone();
+                round_trips(UdpHeader { frag, addr }).await;
+            }
+        }
+    }
+
+    #[test]
+    fn ipv4_addresses_take_six_bytes() {
+        let buf = [0x01, 10, 1, 2, 3, 0x14, 0xE9, 0xAA, 0xBB];
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        assert_eq!(
+            Addr::decode(&buf).unwrap(),
+            Decoded::Done(Addr::Ip(ip, 5353), 7)
+        );
+        let header = UdpHeader::new(Addr::Ip(ip, 5353));
+        let datagram = header.encapsulate(b"payload");
+        assert_eq!(
+            datagram,
+            [&[0, 0, 0, 0x01, 10, 1, 2, 3, 0x14, 0xE9][..], b"payload"]
+                .concat()
+        );
+        let (parsed, payload) = UdpHeader::parse(&datagram).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, b"payload");
+        assert!(UdpHeader::parse(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn malformed_messages_fail_early() {
+        // wrong versions are caught before the rest arrives
+        assert!(matches!(
+            Greeting::decode(&[0x04]),
+            Err(Error::VersionMismatch)
+        ));
+        assert!(matches!(
+            Request::decode(&[0x05, 0x09]),
+            Err(Error::CmdNotSupported(0x09))
+        ));
+        assert!(matches!(
+            Request::decode(&[0x05, 0x01, 0x00, 0x05]),
+            Err(Error::AddressTypeNotSupported)
+        ));
+        assert!(matches!(UserPass::decode(&[0x05]), Err(Error::AuthFailed)));
+        assert!(matches!(
+            UserPass::decode(&[0x01, 0x01, 0xFF, 0x00]),
+            Err(Error::AuthFailed)
+        ));
+        let mut long = vec![0x03, 254];
+        long.extend([b'a'; 254]);
+        assert!(matches!(Addr::decode(&long), Err(Error::InvalidDomain(_))));
+        assert!(matches!(
+            Addr::decode(&[0x03, 0x01, 0xFF]),
+            Err(Error::InvalidDomain(_))
+        ));
+        // the null address is only ever sent, never accepted
+        assert!(matches!(
+            Addr::decode(&[0x00, 0x00, 0x00]),
+            Err(Error::AddressTypeNotSupported)
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+//! The exact bytes RFC 1928 and RFC 1929 have a server answer each failure
+//! with, driven over in-memory connections.
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{self, AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpSocket},
+    time::timeout,
+};
+
+use crate::{
+    Addr, Cmd, FilterResult, MemoryConnector, MemoryListener, Resolver, Server,
+    ServerHandle, Timeouts, Upstream,
+    codec::{self, Encode as _},
+    error::Error,
+    filter::BoxFuture,
+    resolve::Lookup,
+};
+
+/// A failure reply, its address is always `0.0.0.0:0`.
+fn failure(code: u8) -> Vec<u8> {
+    vec![0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+}
+
+/// The no-auth greeting followed by a request.
+fn request(cmd: Cmd, addr: Addr) -> Vec<u8> {
+    let mut sent = vec![0x05, 0x01, 0x00];
+    sent.extend(codec::Request { cmd, addr }.to_vec());
+    sent
+}
+
+fn connect(addr: SocketAddr) -> Vec<u8> {
+    request(Cmd::Connect, Addr::from_ip_addr(addr.ip(), addr.port()))
+}
+
+/// A server on loopback's side of the private network guard, set up
+/// further by `configure`.
+async fn serve(
+    configure: impl FnOnce(&mut Server),
+) -> (ServerHandle, MemoryConnector) {
+    let (listener, connector) = MemoryListener::new();
+    let mut s = Server::builder().listener(listener).build().await.unwrap();
+    s.allow_private_network("127.0.0.0/8".parse().unwrap(), None);
+    configure(&mut s);
+    (s.spawn(), connector)
+}
+
+/// Sends `sent` and returns everything the server answers until it closes
+/// the connection.
+async fn exchange(connector: &MemoryConnector, sent: &[u8]) -> Vec<u8> {
+    let mut stream = connector.connect().await.unwrap();
+    stream.write_all(sent).await.unwrap();
+    let mut answer = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut answer))
+        .await
+        .expect("server to close the connection")
+        .unwrap();
+    answer
+}
+
+/// A port nothing listens on for as long as the socket is kept, it is
+/// bound without listening so no other test can take it meanwhile.
+fn closed_port() -> (TcpSocket, SocketAddr) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    (socket, addr)
+}
+
+struct Unresolvable;
+
+impl Resolver for Unresolvable {
+    fn resolve<'a>(
+        &'a self,
+        _domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>> {
+        Box::pin(async { Err(Error::HostUnreachable) })
+    }
+}
+
+#[tokio::test]
+async fn requests_the_server_cannot_parse() {
+    let (handle, connector) = serve(|_| {}).await;
+
+    let unknown_cmd = [0x05, 0x01, 0x00, 0x05, 0x09, 0x00, 0x01];
+    assert_eq!(exchange(&connector, &unknown_cmd).await[2..], failure(0x07));
+    let unknown_atyp = [0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x02];
+    assert_eq!(
+        exchange(&connector, &unknown_atyp).await[2..],
+        failure(0x08)
+    );
+    let wrong_version = [0x05, 0x01, 0x00, 0x04, 0x01, 0x00, 0x01];
+    assert_eq!(
+        exchange(&connector, &wrong_version).await[2..],
+        failure(0x01)
+    );
+    // not SOCKS at all, there's no telling what the client understands
+    assert!(exchange(&connector, &[0x06, 0x01, 0x00]).await.is_empty());
+    handle.abort().await;
+}
+
+#[tokio::test]
+async fn authentication_failures() {
+    let (handle, connector) = serve(|s| {
+        s.set_authenticator(|username, password| {
+            username == "user" && password == "pass"
+        });
+    })
+    .await;
+
+    let no_auth = [0x05, 0x01, 0x00];
+    assert_eq!(exchange(&connector, &no_auth).await, [0x05, 0xFF]);
+    let mut wrong_password = vec![0x05, 0x01, 0x02];
+    wrong_password.extend([0x01, 4, b'u', b's', b'e', b'r', 1, b'x']);
+    assert_eq!(
+        exchange(&connector, &wrong_password).await,
+        [0x05, 0x02, 0x01, 0x01]
+    );
+    handle.abort().await;
+}
+
+#[tokio::test]
+async fn connect_failures() {
+    let (_closed_socket, closed) = closed_port();
+    let (_blocked_socket, blocked) = closed_port();
+    let (handle, connector) = serve(|s| {
+        s.set_resolver(Unresolvable);
+        s.add_filter(move |ctx| match &ctx.addr {
+            Addr::Ip(_, port) if *port == blocked.port() => FilterResult::Deny,
+            _ => FilterResult::Allow,
+        });
+    })
+    .await;
+
+    assert_eq!(
+        exchange(&connector, &connect(blocked)).await[2..],
+        failure(0x02)
+    );
+    let unresolvable = Addr::Domain("nowhere.test".into(), 80);
+    assert_eq!(
+        exchange(&connector, &request(Cmd::Connect, unresolvable)).await[2..],
+        failure(0x04)
+    );
+    assert_eq!(
+        exchange(&connector, &connect(closed)).await[2..],
+        failure(0x05)
+    );
+    handle.abort().await;
+}
+
+#[tokio::test]
+async fn connects_which_take_too_long() {
+    // an upstream proxy which never answers the greeting
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    let (handle, connector) = serve(|s| {
+        s.set_upstream(Upstream::Socks5 {
+            addr: Addr::from_ip_addr(silent_addr.ip(), silent_addr.port()),
+            credentials: None,
+        });
+        s.set_timeouts(Timeouts {
+            connect: Duration::from_millis(100),
+            ..s.timeouts()
+        });
+    })
+    .await;
+
+    let (_target_socket, target) = closed_port();
+    assert_eq!(
+        exchange(&connector, &connect(target)).await[2..],
+        failure(0x06)
+    );
+    drop(silent);
+    handle.abort().await;
+}
+
+#[tokio::test]
+async fn redirects_to_overlong_domains() {
+    // an upstream the redirect would have to be encoded for
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (handle, connector) = serve(|s| {
+        s.set_upstream(Upstream::Socks5 {
+            addr: Addr::from_ip_addr(upstream_addr.ip(), upstream_addr.port()),
+            credentials: None,
+        });
+        s.add_filter(|_: &_| {
+            FilterResult::Redirect(Addr::Domain("a".repeat(300), 443))
+        });
+    })
+    .await;
+
+    let (_target_socket, target) = closed_port();
+    assert_eq!(
+        exchange(&connector, &connect(target)).await[2..],
+        failure(0x01)
+    );
+    drop(upstream);
+    handle.abort().await;
+}
+
+#[test]
+fn dialing_errors_map_to_reply_codes() {
+    let kinds = [
+        (io::ErrorKind::ConnectionRefused, 0x05),
+        (io::ErrorKind::HostUnreachable, 0x04),
+        (io::ErrorKind::NetworkUnreachable, 0x03),
+        (io::ErrorKind::NetworkDown, 0x03),
+        (io::ErrorKind::TimedOut, 0x06),
+        (io::ErrorKind::ConnectionReset, 0x01),
+        (io::ErrorKind::UnexpectedEof, 0x01),
+    ];
+    for (kind, code) in kinds {
+        let err = Error::dialing(io::Error::from(kind));
+        assert_eq!(err.to_u8(), code, "{kind}");
+    }
+    // the client's own stream timing out is no TTL
+    let err = Error::from(io::Error::from(io::ErrorKind::TimedOut));
+    assert_eq!(err.to_u8(), 0x01);
+}
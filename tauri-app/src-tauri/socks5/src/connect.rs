@@ -0,0 +1,92 @@
+use std::{collections::VecDeque, io, net::SocketAddr, time::Duration};
+
+use log::trace;
+use tokio::{net::TcpStream, task::JoinSet, time::sleep};
+
+/// How long an attempt gets before the next address is tried alongside it,
+/// the value RFC 8305 recommends.
+pub(crate) const CONNECTION_ATTEMPT_DELAY: Duration =
+    Duration::from_millis(250);
+
+/// Orders addresses the way RFC 8305 section 4 asks for: alternating between
+/// address families, starting with the family of the first address.
+fn interleave(addrs: &[SocketAddr]) -> VecDeque<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return VecDeque::new();
+    };
+    let (mut preferred, mut other): (VecDeque<SocketAddr>, VecDeque<_>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut ordered = VecDeque::with_capacity(addrs.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to whichever of `addrs` answers first. A new attempt starts
+/// every `attempt_delay`, or straight away once the previous one failed, and
+/// the attempts still running are cancelled as soon as one succeeds.
+pub(crate) async fn happy_eyeballs(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+) -> io::Result<TcpStream> {
+    fn start_next(
+        attempts: &mut JoinSet<io::Result<TcpStream>>,
+        pending: &mut VecDeque<SocketAddr>,
+    ) {
+        if let Some(addr) = pending.pop_front() {
+            trace!("connecting to {addr}");
+            attempts.spawn(TcpStream::connect(addr));
+        }
+    }
+
+    let mut pending = interleave(addrs);
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    start_next(&mut attempts, &mut pending);
+    while !attempts.is_empty() {
+        tokio::select! {
+            res = attempts.join_next() => {
+                match res.expect("attempts to not be empty") {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => last_err = Some(e),
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                }
+                start_next(&mut attempts, &mut pending);
+            }
+            _ = sleep(attempt_delay), if !pending.is_empty() => {
+                start_next(&mut attempts, &mut pending);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::interleave;
+
+    #[test]
+    fn interleaves_address_families() {
+        let addrs: Vec<SocketAddr> =
+            ["[::1]:1", "[::2]:1", "[::3]:1", "1.0.0.1:1", "1.0.0.2:1"]
+                .iter()
+                .map(|addr| addr.parse().unwrap())
+                .collect();
+        let ordered = interleave(&addrs)
+            .into_iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ordered,
+            ["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "[::3]:1"]
+        );
+    }
+}
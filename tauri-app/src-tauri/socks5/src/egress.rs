@@ -0,0 +1,173 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{Addr, auth::Identity};
+
+/// Which destinations the proxy may reach at all, on top of the filters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", content = "hosts", rename_all = "snake_case")]
+pub enum Egress {
+    #[default]
+    On,
+    Off,
+    /// Only these hosts and their subdomains, `example.com` lets through
+    /// `example.com` and `API.example.com.`. IPs have to match exactly.
+    AllowList(Vec<String>),
+}
+
+impl Egress {
+    /// Whether `host` may be reached, `None` standing for a UDP association
+    /// whose datagrams are checked one by one.
+    fn permits(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (Egress::On, _) => true,
+            (Egress::Off, _) => false,
+            (Egress::AllowList(_), None) => true,
+            (Egress::AllowList(hosts), Some(host)) => {
+                hosts.iter().any(|allowed| allows(allowed, host))
+            }
+        }
+    }
+}
+
+/// Whether the allow-list entry `allowed` covers `host`.
+fn allows(allowed: &str, host: &str) -> bool {
+    // DNS names are case-insensitive and may end in the root's dot
+    let normalize = |name: &str| name.trim_end_matches('.').to_lowercase();
+    let (allowed, host) = (normalize(allowed), normalize(host));
+    match (allowed.parse::<IpAddr>(), host.parse::<IpAddr>()) {
+        (Ok(allowed), Ok(host)) => allowed == host,
+        (Ok(_), Err(_)) | (Err(_), Ok(_)) => false,
+        (Err(_), Err(_)) => host
+            .strip_suffix(&allowed)
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.')),
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    egress: Egress,
+    offline: HashSet<Identity>,
+}
+
+/// The [`Egress`] mode and the identities kept offline, changed at runtime
+/// and shared by every clone of a server. Running connections watch it and
+/// close once they aren't permitted anymore.
+#[derive(Debug, Default)]
+pub(crate) struct KillSwitch {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl KillSwitch {
+    pub fn egress(&self) -> Egress {
+        self.lock().egress.clone()
+    }
+
+    pub fn set_egress(&self, egress: Egress) {
+        self.lock().egress = egress;
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_offline(&self, identity: &Identity) -> bool {
+        self.lock().offline.contains(identity)
+    }
+
+    pub fn set_offline(&self, identity: &Identity, offline: bool) {
+        let mut state = self.lock();
+        let changed = match offline {
+            true => state.offline.insert(identity.clone()),
+            false => state.offline.remove(identity),
+        };
+        drop(state);
+        if changed {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Whether `identity` may reach `addr`, see [`Egress::permits`] for
+    /// `None`.
+    pub fn permits(&self, identity: &Identity, addr: Option<&Addr>) -> bool {
+        let state = self.lock();
+        let host = addr.map(|addr| addr.host().unwrap_or_default());
+        !state.offline.contains(identity)
+            && state.egress.permits(host.as_deref())
+    }
+
+    /// Resolves once `identity` may no longer reach `addr`.
+    pub async fn revoked(
+        self: Arc<Self>,
+        identity: Identity,
+        addr: Option<Addr>,
+    ) {
+        loop {
+            // registered before checking so a change in between isn't missed
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if !self.permits(&identity, addr.as_ref()) {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("kill switch to not be poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::{Egress, KillSwitch};
+    use crate::{Addr, Identity};
+
+    #[test]
+    fn allow_lists_match_subdomains() {
+        let egress =
+            Egress::AllowList(vec!["Example.com.".into(), "10.0.0.1".into()]);
+        assert!(egress.permits(Some("example.com")));
+        assert!(egress.permits(Some("api.example.com")));
+        assert!(!egress.permits(Some("badexample.com")));
+        assert!(!egress.permits(Some("example.com.evil")));
+        assert!(egress.permits(Some("10.0.0.1")));
+        assert!(!egress.permits(Some("10.0.0.10")));
+        assert!(!egress.permits(Some("x.10.0.0.1")));
+        assert!(egress.permits(Some("API.Example.com")));
+        assert!(egress.permits(Some("example.com.")));
+        assert!(!egress.permits(Some("")));
+    }
+
+    #[tokio::test]
+    async fn connections_are_revoked_when_the_switch_flips() {
+        let switch = Arc::new(KillSwitch::default());
+        let app = Identity::User("doc-1".into());
+        let addr = Addr::Domain("example.com".into(), 443);
+        let revoked =
+            tokio::spawn(switch.clone().revoked(app.clone(), Some(addr)));
+
+        // still permitted, nothing happens
+        switch.set_egress(Egress::AllowList(vec!["example.com".into()]));
+        switch.set_offline(&Identity::Anonymous, true);
+        tokio::task::yield_now().await;
+        assert!(!revoked.is_finished());
+
+        switch.set_offline(&app, true);
+        timeout(Duration::from_secs(1), revoked)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!switch.permits(&app, None));
+        assert!(switch.permits(&Identity::User("doc-2".into()), None));
+    }
+}
@@ -13,10 +13,18 @@ pub enum Error {
     InvalidDomain(String),
     #[error("invalid auth method")]
     InvalidAuth,
+    #[error("authentication failed")]
+    AuthFailed,
     #[error("version from client is not 5")]
     VersionMismatch,
+    #[error("malformed request: {0}")]
+    Malformed(&'static str),
     #[error("connection not allowed by ruleset")]
     BreaksRuleset,
+    #[error("too many new connections")]
+    RateLimited,
+    #[error("too many open connections")]
+    TooManyConnections,
     #[error("network unreachable")]
     NetworkUnreachable,
     #[error("host unreachable")]
@@ -25,8 +33,9 @@ pub enum Error {
     ConnectionRefused,
     #[error("TTL expired")]
     TtlExpired,
-    #[error("unsupported command: {0}")]
-    CmdNotSupported(Cmd),
+    /// Carries the command byte as sent, which may be no [`Cmd`] at all.
+    #[error("unsupported command: {0:#04x}")]
+    CmdNotSupported(u8),
     #[error("address type not supported")]
     AddressTypeNotSupported,
     #[error("internal error: {0}")]
@@ -40,12 +49,31 @@ impl From<FromUtf8Error> for Error {
 }
 
 impl Error {
+    /// A failure to connect to a destination as the variant with the
+    /// matching reply code. Only dialing gets these, an error on the
+    /// client's own stream stays an I/O error.
+    pub(crate) fn dialing(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Error::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => Error::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::NetworkDown => {
+                Error::NetworkUnreachable
+            }
+            io::ErrorKind::TimedOut => Error::TtlExpired,
+            _ => Error::Io(err),
+        }
+    }
+
     pub fn to_u8(&self) -> u8 {
         match self {
             Error::Io(_) => 0x01,
             Error::InvalidAuth => 0xFF,
+            Error::AuthFailed => 0x01,
             Error::VersionMismatch => 0x01,
+            Error::Malformed(_) => 0x01,
             Error::BreaksRuleset => 0x02,
+            Error::RateLimited => 0x02,
+            Error::TooManyConnections => 0x01,
             Error::NetworkUnreachable => 0x03,
             Error::HostUnreachable => 0x04,
             Error::ConnectionRefused => 0x05,
@@ -56,6 +84,47 @@ impl Error {
             Error::Internal(_) => 0x01,
         }
     }
+
+    /// The error a SOCKS5 reply `code` to `cmd` stands for, `None` for
+    /// success. Codes which several errors share come back as the most
+    /// general of them.
+    pub fn from_reply(code: u8, cmd: Cmd) -> Option<Self> {
+        let err = match code {
+            0x00 => return None,
+            0x02 => Error::BreaksRuleset,
+            0x03 => Error::NetworkUnreachable,
+            0x04 => Error::HostUnreachable,
+            0x05 => Error::ConnectionRefused,
+            0x06 => Error::TtlExpired,
+            0x07 => Error::CmdNotSupported(cmd.into()),
+            0x08 => Error::AddressTypeNotSupported,
+            _ => Error::Internal("general SOCKS server failure"),
+        };
+        Some(err)
+    }
+
+    /// A short name for the kind of error which stays the same between
+    /// occurrences, unlike the message.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::InvalidDomain(_) => "invalid_domain",
+            Error::InvalidAuth => "invalid_auth",
+            Error::AuthFailed => "auth_failed",
+            Error::VersionMismatch => "version_mismatch",
+            Error::Malformed(_) => "malformed",
+            Error::BreaksRuleset => "ruleset",
+            Error::RateLimited => "rate_limited",
+            Error::TooManyConnections => "too_many_connections",
+            Error::NetworkUnreachable => "network_unreachable",
+            Error::HostUnreachable => "host_unreachable",
+            Error::ConnectionRefused => "connection_refused",
+            Error::TtlExpired => "ttl_expired",
+            Error::CmdNotSupported(_) => "cmd_not_supported",
+            Error::AddressTypeNotSupported => "address_type_not_supported",
+            Error::Internal(_) => "internal",
+        }
+    }
 }
 
 impl From<Error> for u8 {
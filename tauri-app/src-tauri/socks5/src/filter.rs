@@ -0,0 +1,367 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, trace};
+
+use crate::{
+    Addr, Cmd, Error,
+    auth::Identity,
+    egress::KillSwitch,
+    guard::PrivateNetworkGuard,
+    resolve::{CachingResolver, Resolver, SystemResolver},
+    upstream::Upstream,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Everything known about a connection when it is filtered.
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    /// Address of the client talking to the proxy, `0.0.0.0:0` for clients
+    /// on transports without addresses such as Unix sockets.
+    pub peer: SocketAddr,
+    pub cmd: Cmd,
+    pub identity: Identity,
+    /// The destination. For the second pass of a BIND this is the peer which
+    /// connected, for a UDP associate the destination of one datagram.
+    pub addr: Addr,
+    /// What `addr` resolves to, minus anything the private network guard
+    /// blocks. The proxy only ever connects to these. Empty for domains
+    /// which are handed to an upstream proxy unresolved, the guard doesn't
+    /// apply to those. Also empty for domains which didn't resolve at
+    /// first; they are resolved and guarded again once the filters are
+    /// done, and refused if they still don't resolve.
+    pub resolved: Vec<IpAddr>,
+    /// Set on the first pass over a CONNECT to a bare IP with
+    /// [`HostPeeking`](crate::HostPeeking) on. Once it is allowed the filters
+    /// run again for the host the client's first bytes name, or for the IP
+    /// if they name none, so filters which ask the user can let this pass
+    /// through and ask on the last one.
+    pub will_peek: bool,
+}
+
+impl ConnectionContext {
+    pub fn new(
+        peer: SocketAddr,
+        cmd: Cmd,
+        identity: Identity,
+        addr: Addr,
+    ) -> Self {
+        Self {
+            peer,
+            cmd,
+            identity,
+            addr,
+            resolved: Vec::new(),
+            will_peek: false,
+        }
+    }
+
+    /// The first resolved address together with the destination port.
+    pub fn target(&self) -> Result<SocketAddr, Error> {
+        let ip = self
+            .resolved
+            .first()
+            .ok_or(Error::Internal("destination was never resolved"))?;
+        Ok(SocketAddr::new(*ip, self.addr.port()))
+    }
+
+    /// Every resolved address together with the destination port.
+    pub fn targets(&self) -> Vec<SocketAddr> {
+        let port = self.addr.port();
+        self.resolved
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect()
+    }
+}
+
+/// Caps a filter can put on a connection it allows.
+///
+/// They apply to relayed TCP streams, UDP associations ignore them. Timeouts
+/// left unset fall back to the server's [`Timeouts`](crate::Timeouts), set
+/// ones replace them even when they are longer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes per second in each direction.
+    pub bytes_per_sec: Option<u64>,
+    pub connect_timeout: Option<Duration>,
+    /// The connection is closed once no data went either way for this long.
+    pub idle_timeout: Option<Duration>,
+    /// The connection is closed once it has been open this long.
+    pub max_lifetime: Option<Duration>,
+    /// How long a BIND waits for its inbound connection.
+    pub bind_timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Combines two sets of limits, keeping the stricter value of each.
+    pub fn merge(self, other: Limits) -> Limits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Limits {
+            bytes_per_sec: min(self.bytes_per_sec, other.bytes_per_sec),
+            connect_timeout: min(self.connect_timeout, other.connect_timeout),
+            idle_timeout: min(self.idle_timeout, other.idle_timeout),
+            max_lifetime: min(self.max_lifetime, other.max_lifetime),
+            bind_timeout: min(self.bind_timeout, other.bind_timeout),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FilterResult {
+    Allow,
+    /// Rejected with the "connection not allowed by ruleset" reply.
+    Deny,
+    /// Rejected with the reply code of the given error.
+    DenyWith(Error),
+    /// Connect somewhere else instead. The filters after this one see the
+    /// new destination.
+    Redirect(Addr),
+    AllowWithLimits(Limits),
+    /// Connect through this upstream instead of the server's default one.
+    /// Only CONNECTs can be chained.
+    RouteVia(Upstream),
+    /// Count the connection towards this class of
+    /// [`RateLimits::classes`](crate::RateLimits::classes). The filters after
+    /// this one still run, the last class given wins.
+    LimitClass(String),
+}
+
+/// Decides whether a connection may go through.
+///
+/// Plain closures taking a [`ConnectionContext`] are filters already, implement
+/// this directly for filters which need to await something.
+pub trait Filter: Send + Sync {
+    fn check<'a>(
+        &'a self,
+        ctx: &'a ConnectionContext,
+    ) -> BoxFuture<'a, FilterResult>;
+
+    /// How the filter shows up in the audit log, its type name unless
+    /// overridden.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&ConnectionContext) -> FilterResult + Send + Sync,
+{
+    fn check<'a>(
+        &'a self,
+        ctx: &'a ConnectionContext,
+    ) -> BoxFuture<'a, FilterResult> {
+        let res = self(ctx);
+        Box::pin(std::future::ready(res))
+    }
+}
+
+/// The outcome of running every filter.
+#[derive(Debug)]
+pub(crate) struct Decision {
+    /// The context after any redirects, with the destination resolved.
+    pub ctx: ConnectionContext,
+    pub limits: Limits,
+    pub upstream: Upstream,
+    /// The rate limit class the filters put the connection in.
+    pub class: Option<String>,
+    /// The last filter which did more than allow the connection.
+    pub decided_by: Option<String>,
+}
+
+/// What refusals by the kill switch are put down to.
+pub(crate) const KILL_SWITCH: &str = "kill switch";
+
+/// A connection the policy turned down, and who did.
+#[derive(Debug)]
+pub(crate) struct Refused {
+    pub err: Error,
+    /// The filter or built-in check which refused, `None` when the
+    /// destination couldn't be looked up or reached.
+    pub by: Option<String>,
+}
+
+impl Refused {
+    pub fn by(err: Error, by: impl Into<String>) -> Self {
+        Self {
+            err,
+            by: Some(by.into()),
+        }
+    }
+}
+
+impl From<Error> for Refused {
+    fn from(err: Error) -> Self {
+        Self { err, by: None }
+    }
+}
+
+impl From<Refused> for Error {
+    fn from(refused: Refused) -> Self {
+        refused.err
+    }
+}
+
+/// The filters together with the built-in guard and the resolver they work
+/// with, shared by every connection of a server.
+#[derive(Clone)]
+pub(crate) struct Policy {
+    pub filters: Vec<Arc<dyn Filter>>,
+    pub guard: PrivateNetworkGuard,
+    pub resolver: Arc<dyn Resolver>,
+    pub upstream: Upstream,
+    /// Shared by every copy of the policy, it changes at runtime.
+    pub kill_switch: Arc<KillSwitch>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            filters: Vec::new(),
+            guard: PrivateNetworkGuard::default(),
+            resolver: Arc::new(CachingResolver::new(SystemResolver)),
+            upstream: Upstream::Direct,
+            kill_switch: Arc::default(),
+        }
+    }
+}
+
+impl Policy {
+    /// Resolves the destination and runs the filters in order. The first one
+    /// to deny decides; redirects are resolved again before the next filter
+    /// runs. The kill switch is checked before anything else and again for
+    /// wherever the filters redirected to.
+    ///
+    /// Domains aren't resolved when the server's upstream takes them. A
+    /// domain which doesn't resolve locally is only refused once the filters
+    /// are done, one of them may still route it to an upstream.
+    pub async fn evaluate(
+        &self,
+        mut ctx: ConnectionContext,
+    ) -> Result<Decision, Refused> {
+        self.check_kill_switch(&ctx)?;
+        let (mut upstream, strict) = match ctx.cmd {
+            Cmd::Connect => (self.upstream.clone(), false),
+            _ => (Upstream::Direct, true),
+        };
+        self.resolve(&mut ctx, &upstream, strict).await?;
+        let mut limits = Limits::default();
+        let mut class = None;
+        let mut decided_by = None;
+        for filter in &self.filters {
+            let res = filter.check(&ctx).await;
+            if !matches!(res, FilterResult::Allow) {
+                decided_by = Some(filter.name().to_string());
+            }
+            match res {
+                FilterResult::Allow => (),
+                FilterResult::Deny => {
+                    return Err(Refused::by(
+                        Error::BreaksRuleset,
+                        filter.name(),
+                    ));
+                }
+                FilterResult::DenyWith(e) => {
+                    return Err(Refused::by(e, filter.name()));
+                }
+                FilterResult::Redirect(addr) => {
+                    // it may have to fit a SOCKS5 request to an upstream
+                    let addr = match addr {
+                        Addr::Domain(domain, port) => {
+                            Addr::try_from_domain(domain, port)
+                                .await
+                                .map_err(|e| Refused::by(e, filter.name()))?
+                        }
+                        addr => addr,
+                    };
+                    trace!("redirecting {:?} to {addr:?}", ctx.addr);
+                    ctx.addr = addr;
+                    self.resolve(&mut ctx, &upstream, strict).await?;
+                }
+                FilterResult::AllowWithLimits(l) => limits = limits.merge(l),
+                FilterResult::RouteVia(route) if ctx.cmd == Cmd::Connect => {
+                    upstream = route;
+                }
+                FilterResult::RouteVia(_) => {
+                    let err = Error::CmdNotSupported(ctx.cmd.into());
+                    return Err(Refused::by(err, filter.name()));
+                }
+                FilterResult::LimitClass(c) => class = Some(c),
+            }
+        }
+        if upstream.is_direct() && ctx.resolved.is_empty() {
+            self.resolve(&mut ctx, &upstream, true).await?;
+        }
+        self.check_kill_switch(&ctx)?;
+        Ok(Decision {
+            ctx,
+            limits,
+            upstream,
+            class,
+            decided_by,
+        })
+    }
+
+    fn check_kill_switch(
+        &self,
+        ctx: &ConnectionContext,
+    ) -> Result<(), Refused> {
+        if !self.kill_switch.permits(&ctx.identity, Some(&ctx.addr)) {
+            let err = Error::BreaksRuleset;
+            return Err(Refused::by(err, KILL_SWITCH));
+        }
+        Ok(())
+    }
+
+    /// Resolves `ctx.addr` and drops the addresses the guard blocks, so
+    /// neither the filters nor the connection ever see them. Domains going
+    /// to an upstream are left for it to resolve, and so are ones which
+    /// don't resolve unless `strict`.
+    async fn resolve(
+        &self,
+        ctx: &mut ConnectionContext,
+        upstream: &Upstream,
+        strict: bool,
+    ) -> Result<(), Refused> {
+        ctx.resolved.clear();
+        let is_domain = matches!(ctx.addr, Addr::Domain(..));
+        if is_domain && !upstream.is_direct() {
+            return Ok(());
+        }
+        let port = ctx.addr.port();
+        let mut resolved = match ctx.addr.lookup(self.resolver.as_ref()).await {
+            Ok(v) => v,
+            Err(e) if is_domain && !strict => {
+                debug!("could not resolve {:?} yet: {e}", ctx.addr);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if ctx.cmd != Cmd::Bind {
+            resolved.retain(|ip| {
+                let permitted = self.guard.permits(SocketAddr::new(*ip, port));
+                if !permitted {
+                    debug!("guard blocked {:?} resolving to {ip}", ctx.addr);
+                }
+                permitted
+            });
+            if resolved.is_empty() {
+                let guard = "private network guard";
+                return Err(Refused::by(Error::BreaksRuleset, guard));
+            }
+        }
+        ctx.resolved = resolved;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use super::Error;
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Fails if `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, Error> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return Err(Error::Internal("network prefix longer than address"));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    const fn v4(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+            prefix,
+        }
+    }
+
+    const fn v6(bits: u128, prefix: u8) -> Self {
+        Self {
+            addr: IpAddr::V6(Ipv6Addr::from_bits(bits)),
+            prefix,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn mask(bits: u128, prefix: u8, len: u8) -> u128 {
+            match prefix {
+                0 => 0,
+                _ => bits >> (len - prefix),
+            }
+        }
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(net.to_bits().into(), self.prefix, 32)
+                    == mask(ip.to_bits().into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(net.to_bits(), self.prefix, 128)
+                    == mask(ip.to_bits(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { addr, prefix }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Internal("invalid network, expected ip/prefix");
+        match s.split_once('/') {
+            Some((addr, prefix)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(s.parse::<IpAddr>().map_err(|_| invalid())?.into()),
+        }
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Networks a sandbox has no business reaching: the user's machine and
+/// whatever is on their local networks.
+const PRIVATE_NETWORKS: [IpNet; 13] = [
+    // "this network", 0.0.0.0 reaches the local machine on most systems
+    IpNet::v4(0, 0, 0, 0, 8),
+    IpNet::v4(127, 0, 0, 0, 8),
+    // RFC 1918
+    IpNet::v4(10, 0, 0, 0, 8),
+    IpNet::v4(172, 16, 0, 0, 12),
+    IpNet::v4(192, 168, 0, 0, 16),
+    // carrier-grade NAT, RFC 6598
+    IpNet::v4(100, 64, 0, 0, 10),
+    IpNet::v4(169, 254, 0, 0, 16),
+    IpNet::v4(255, 255, 255, 255, 32),
+    IpNet::v6(0, 128),
+    IpNet::v6(1, 128),
+    // unique local addresses, RFC 4193
+    IpNet::v6(0xfc00 << 112, 7),
+    IpNet::v6(0xfe80 << 112, 10),
+    // site-local, deprecated but still routed by some stacks
+    IpNet::v6(0xfec0 << 112, 10),
+];
+
+/// Blocks destinations on loopback, link-local, RFC 1918, CGNAT and IPv6
+/// unique local networks. It is on by default and checks the resolved
+/// address, so a domain can't be pointed at the user's machine to get around
+/// it.
+///
+/// Only destinations the proxy sends to are guarded: CONNECT targets and the
+/// destinations of UDP datagrams. Domains handed to an upstream proxy are
+/// resolved on its side, where the guard can't see the answer, so it does
+/// not apply to them; IPs going upstream are still checked. IPv4-mapped IPv6
+/// addresses are checked as the IPv4 address they reach.
+#[derive(Debug, Clone)]
+pub struct PrivateNetworkGuard {
+    enabled: bool,
+    exceptions: Vec<(IpNet, Option<u16>)>,
+}
+
+impl Default for PrivateNetworkGuard {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            exceptions: Vec::new(),
+        }
+    }
+}
+
+impl PrivateNetworkGuard {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Lets destinations in `net` through, optionally only on `port`.
+    pub fn allow(&mut self, net: IpNet, port: Option<u16>) {
+        self.exceptions.push((net, port));
+    }
+
+    pub fn is_private(ip: IpAddr) -> bool {
+        // IPv4-mapped addresses reach the same place as the IPv4 address
+        let ip = ip.to_canonical();
+        PRIVATE_NETWORKS.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn permits(&self, target: SocketAddr) -> bool {
+        let ip = target.ip().to_canonical();
+        if !self.enabled || !Self::is_private(ip) {
+            return true;
+        }
+        self.exceptions.iter().any(|(net, port)| {
+            net.contains(ip) && port.is_none_or(|p| p == target.port())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{IpNet, PrivateNetworkGuard};
+
+    fn private(ip: &str) -> bool {
+        PrivateNetworkGuard::is_private(ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn classifies_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "0.0.0.0",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "100.64.0.1",
+            "169.254.169.254",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ] {
+            assert!(private(ip), "{ip} should be private");
+        }
+        for ip in [
+            "1.1.1.1",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!private(ip), "{ip} should be public");
+        }
+    }
+
+    #[test]
+    fn exceptions_match_network_and_port() {
+        let mut guard = PrivateNetworkGuard::default();
+        guard.allow("127.0.0.0/8".parse().unwrap(), Some(8080));
+        guard.allow("10.0.0.5".parse().unwrap(), None);
+
+        let permits =
+            |addr: &str| guard.permits(addr.parse::<SocketAddr>().unwrap());
+        assert!(permits("127.0.0.2:8080"));
+        assert!(!permits("127.0.0.2:8081"));
+        assert!(permits("10.0.0.5:22"));
+        assert!(!permits("10.0.0.6:22"));
+        // the exceptions are IPv4, the same addresses mapped into IPv6 too
+        assert!(permits("[::ffff:127.0.0.2]:8080"));
+        assert!(!permits("[::ffff:127.0.0.2]:8081"));
+        assert!(!permits("[::ffff:10.0.0.6]:22"));
+        assert!(permits("93.184.215.14:443"));
+
+        guard.set_enabled(false);
+        assert!(guard.permits("192.168.0.1:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_networks() {
+        let net: IpNet = "fc00::/7".parse().unwrap();
+        assert_eq!(net.to_string(), "fc00::/7");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not an ip".parse::<IpNet>().is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::{net::SocketAddr, time::Duration};
+
+use log::{debug, error};
+use tokio::{
+    sync::oneshot,
+    task::{AbortHandle, JoinHandle},
+    time::timeout,
+};
+
+use crate::{server::tcp_addr, transport::ListenAddr};
+
+/// Aborts a task once dropped, ties a proxy's lifetime to the connection task
+/// which started it.
+pub(crate) struct AbortOnDrop(AbortHandle);
+
+impl AbortOnDrop {
+    pub fn new<T>(handle: &JoinHandle<T>) -> Self {
+        Self(handle.abort_handle())
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The connections of a server which may still be transferring data.
+#[derive(Default)]
+pub struct Connections {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Connections {
+    pub(crate) fn push(&mut self, handle: JoinHandle<()>) {
+        self.handles.retain(|h| !h.is_finished());
+        self.handles.push(handle);
+    }
+
+    /// Number of connections which have not finished yet.
+    pub fn len(&self) -> usize {
+        self.handles.iter().filter(|h| !h.is_finished()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Aborts every connection immediately.
+    pub fn abort(self) {
+        for handle in self.handles {
+            handle.abort();
+        }
+    }
+
+    /// Waits up to `grace` for the connections to finish on their own and
+    /// aborts whichever are still running afterwards.
+    pub async fn drain(self, grace: Duration) {
+        let aborters = self
+            .handles
+            .iter()
+            .map(JoinHandle::abort_handle)
+            .collect::<Vec<_>>();
+        let wait_all = async {
+            for handle in self.handles {
+                let _ = handle.await;
+            }
+        };
+        if timeout(grace, wait_all).await.is_err() {
+            debug!("aborting connections after grace period");
+            for aborter in aborters {
+                aborter.abort();
+            }
+        }
+    }
+}
+
+/// Handle to a server running on a background task, see
+/// [`Server::spawn`](crate::Server::spawn).
+///
+/// Dropping the handle stops the server from accepting new connections but
+/// leaves the connections which are already running alone.
+pub struct ServerHandle {
+    addrs: Vec<ListenAddr>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Connections>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        addrs: Vec<ListenAddr>,
+        stop: oneshot::Sender<()>,
+        task: JoinHandle<Connections>,
+    ) -> Self {
+        Self { addrs, stop, task }
+    }
+
+    /// See [`Server::addr`](crate::Server::addr).
+    pub fn addr(&self) -> SocketAddr {
+        tcp_addr(&self.addrs)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr().port()
+    }
+
+    pub fn listen_addrs(&self) -> &[ListenAddr] {
+        &self.addrs
+    }
+
+    /// Stops accepting new connections and drains the running ones, aborting
+    /// any which outlive `grace`.
+    pub async fn shutdown(self, grace: Duration) {
+        let _ = self.stop.send(());
+        match self.task.await {
+            Ok(connections) => connections.drain(grace).await,
+            Err(err) => error!("server task failed: {err}"),
+        }
+    }
+
+    /// Stops accepting new connections and aborts every running one.
+    pub async fn abort(self) {
+        self.shutdown(Duration::ZERO).await
+    }
+}
@@ -0,0 +1,348 @@
+use std::net::IpAddr;
+
+use log::trace;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt as _, AsyncWrite, AsyncWriteExt as _,
+};
+
+use crate::{
+    Addr, Error,
+    auth::{Authenticator, Identity},
+};
+
+/// Longest request head accepted from a client.
+const MAX_HEAD: usize = 16 * 1024;
+
+pub(crate) const ESTABLISHED: &[u8] =
+    b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+/// An HTTP/1.1 proxy request, either a `CONNECT host:port` tunnel or a
+/// plain request in absolute form such as `GET http://host/path`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HttpRequest {
+    pub addr: Addr,
+    /// For requests in absolute form, the head to send on to the destination
+    /// in origin form. `None` for `CONNECT`.
+    pub forward: Option<Vec<u8>>,
+    /// From a `Proxy-Authorization: Basic` header.
+    credentials: Option<(String, String)>,
+}
+
+/// Reads a request and checks its credentials. Clients which send garbage or
+/// fail to authenticate are answered with the matching status.
+pub(crate) async fn handshake<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    authenticator: Option<&Authenticator>,
+) -> Result<(Identity, HttpRequest), Error> {
+    let req = match read_head(stream).await.and_then(|head| parse(&head)) {
+        Ok(req) => req,
+        Err(e) => {
+            refuse(stream, &e).await?;
+            return Err(e);
+        }
+    };
+    let Some(authenticator) = authenticator else {
+        return Ok((Identity::Anonymous, req));
+    };
+    match &req.credentials {
+        Some((username, password)) if authenticator(username, password) => {
+            let identity = Identity::User(username.clone());
+            Ok((identity, req))
+        }
+        _ => {
+            refuse(stream, &Error::AuthFailed).await?;
+            Err(Error::AuthFailed)
+        }
+    }
+}
+
+/// Answers with the status matching `err` and asks for credentials if they
+/// were the problem.
+pub(crate) async fn refuse<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    err: &Error,
+) -> Result<(), Error> {
+    let (code, reason) = status(err);
+    let challenge = match code {
+        407 => "Proxy-Authenticate: Basic realm=\"proxy\"\r\n",
+        _ => "",
+    };
+    let res = format!(
+        "HTTP/1.1 {code} {reason}\r\n{challenge}Content-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(res.as_bytes()).await?;
+    Ok(())
+}
+
+pub(crate) fn status(err: &Error) -> (u16, &'static str) {
+    match err {
+        Error::Malformed(_)
+        | Error::InvalidDomain(_)
+        | Error::AddressTypeNotSupported => (400, "Bad Request"),
+        Error::InvalidAuth | Error::AuthFailed => {
+            (407, "Proxy Authentication Required")
+        }
+        Error::BreaksRuleset => (403, "Forbidden"),
+        Error::RateLimited => (429, "Too Many Requests"),
+        Error::TooManyConnections => (503, "Service Unavailable"),
+        Error::CmdNotSupported(_) => (405, "Method Not Allowed"),
+        Error::Io(_)
+        | Error::NetworkUnreachable
+        | Error::HostUnreachable
+        | Error::ConnectionRefused => (502, "Bad Gateway"),
+        Error::TtlExpired => (504, "Gateway Timeout"),
+        Error::VersionMismatch => (505, "HTTP Version Not Supported"),
+        Error::Internal(_) => (500, "Internal Server Error"),
+    }
+}
+
+/// Reads up to and including the empty line ending the head. Whatever the
+/// client sent past it stays in the stream's buffer for the destination.
+async fn read_head<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+) -> Result<Vec<u8>, Error> {
+    let mut head = Vec::new();
+    loop {
+        let available = stream.fill_buf().await?;
+        if available.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let taken = available.len();
+        // the empty line may start in what was read before
+        let searched = head.len().saturating_sub(3);
+        let before = head.len();
+        head.extend_from_slice(available);
+        let end = head[searched..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|at| searched + at + 4);
+        match end {
+            Some(end) if end <= MAX_HEAD => {
+                head.truncate(end);
+                stream.consume(end - before);
+                return Ok(head);
+            }
+            None if head.len() < MAX_HEAD => stream.consume(taken),
+            _ => return Err(Error::Malformed("request head too long")),
+        }
+    }
+}
+
+fn parse(head: &[u8]) -> Result<HttpRequest, Error> {
+    let head = std::str::from_utf8(head)
+        .map_err(|_| Error::Malformed("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Error::Malformed("invalid request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Error::VersionMismatch);
+    }
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect::<Vec<_>>();
+    let credentials = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authorization"))
+        .and_then(|(_, value)| basic_credentials(value));
+    trace!("HTTP proxy request {request_line}");
+
+    if method == "CONNECT" {
+        return Ok(HttpRequest {
+            addr: parse_authority(target, None)?,
+            forward: None,
+            credentials,
+        });
+    }
+    let rest = target
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &target[7..])
+        .ok_or(Error::Malformed("expected an absolute http:// URL"))?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let path = match path.starts_with('?') {
+        true => format!("/{path}"),
+        false => path.to_string(),
+    };
+    // userinfo is not for the destination
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+
+    let mut forward = format!("{method} {path} {version}\r\n");
+    let mut has_host = false;
+    for (name, value) in &headers {
+        let hop_by_hop = [
+            "Proxy-Authorization",
+            "Proxy-Connection",
+            "Connection",
+            "Keep-Alive",
+        ]
+        .iter()
+        .any(|h| name.eq_ignore_ascii_case(h));
+        if hop_by_hop {
+            continue;
+        }
+        has_host |= name.eq_ignore_ascii_case("Host");
+        forward.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !has_host {
+        forward.push_str(&format!("Host: {authority}\r\n"));
+    }
+    // later requests on the connection could be for another host
+    forward.push_str("Connection: close\r\n\r\n");
+    Ok(HttpRequest {
+        addr: parse_authority(authority, Some(80))?,
+        forward: Some(forward.into_bytes()),
+        credentials,
+    })
+}
+
+/// Parses `host:port`, `[v6]:port` or, given a default port, just the host.
+fn parse_authority(
+    authority: &str,
+    default_port: Option<u16>,
+) -> Result<Addr, Error> {
+    let invalid = || Error::Malformed("invalid host or port");
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().map_err(|_| invalid())?)
+        }
+        _ => (authority, default_port.ok_or_else(invalid)?),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(Addr::from_ip_addr(ip, port)),
+        Err(_) if host.len() > 253 => Err(Error::InvalidDomain(host.into())),
+        Err(_) => Ok(Addr::Domain(host.to_string(), port)),
+    }
+}
+
+/// Decodes `Basic <base64 of user:pass>`.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            bits |= value(*c)? << (18 - 6 * i);
+        }
+        let bytes = bits.to_be_bytes();
+        decoded.extend(&bytes[1..chunk.len()]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt as _;
+
+    use super::{HttpRequest, MAX_HEAD, base64_decode, parse, read_head};
+    use crate::Addr;
+
+    #[test]
+    fn parses_connect_requests() {
+        let req = parse(
+            b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\
+            Proxy-Authorization: Basic ZG9jLTE6dG9rZW4=\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            req,
+            HttpRequest {
+                addr: Addr::from_ip_addr("::1".parse().unwrap(), 443),
+                forward: None,
+                credentials: Some(("doc-1".into(), "token".into())),
+            }
+        );
+        assert!(parse(b"CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn rewrites_absolute_form_requests() {
+        let req = parse(
+            b"GET http://user@Example.com:8080?q=1 HTTP/1.1\r\n\
+            Host: Example.com:8080\r\nProxy-Connection: keep-alive\r\n\
+            Accept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.addr, Addr::Domain("Example.com".into(), 8080));
+        assert_eq!(
+            String::from_utf8(req.forward.unwrap()).unwrap(),
+            "GET /?q=1 HTTP/1.1\r\nHost: Example.com:8080\r\nAccept: */*\r\n\
+            Connection: close\r\n\r\n"
+        );
+
+        let req = parse(b"POST http://10.0.0.1/a/b HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(req.addr, Addr::from_ip_addr([10, 0, 0, 1].into(), 80));
+        assert!(parse(b"GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn heads_leave_the_body_unread() {
+        let sent = b"POST http://example.com/ HTTP/1.1\r\n\r\nbody";
+        let mut stream = &sent[..];
+        let head = read_head(&mut stream).await.unwrap();
+        assert_eq!(head, sent[..sent.len() - 4]);
+        assert_eq!(stream, b"body");
+
+        // split right inside the empty line
+        let (first, second) = sent.split_at(sent.len() - 6);
+        let mut stream = first.chain(second);
+        read_head(&mut stream).await.unwrap();
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"body");
+
+        let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+        long.resize(MAX_HEAD + 1, b'a');
+        long.extend(b"\r\n\r\n");
+        assert!(read_head(&mut &long[..]).await.is_err());
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(base64_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64_decode("aGk").unwrap(), b"hi");
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert!(base64_decode("a").is_none());
+        assert!(base64_decode("a*==").is_none());
+    }
+}
@@ -1,44 +1,1560 @@
 pub mod addr;
+mod audit;
+mod auth;
+mod caps;
+pub mod client;
 mod cmd;
+pub mod codec;
+#[cfg(test)]
+mod conformance;
+mod connect;
+mod egress;
 pub mod error;
+pub mod filter;
+pub mod guard;
+mod handle;
+mod http;
+pub mod metrics;
+mod peek;
 mod proxy;
+mod ratelimit;
 mod request;
+pub mod resolve;
 mod response;
 pub mod server;
+mod socks4;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+mod splice;
+mod throttle;
+mod timeouts;
+mod transport;
+pub mod udp;
+pub mod upstream;
 
 pub use addr::Addr;
-use cmd::Cmd;
+pub use audit::{AuditFile, AuditRecord, AuditSink, Phase, Verdict};
+pub use auth::Identity;
+pub use caps::{ConnectionLimits, WhenFull};
+pub use client::Client;
+pub use cmd::Cmd;
+pub use egress::Egress;
 use error::Error;
 use request::Request;
 
-pub use server::FilterResult;
-pub use server::Server;
+pub use filter::{ConnectionContext, Filter, FilterResult, Limits};
+pub use guard::{IpNet, PrivateNetworkGuard};
+pub use handle::{Connections, ServerHandle};
+pub use metrics::{MetricsSnapshot, Stats};
+pub use peek::HostPeeking;
+pub use ratelimit::{Rate, RateLimits};
+pub use resolve::{CachingResolver, Resolver, StaticHosts, SystemResolver};
+pub use server::{Server, ServerBuilder};
+pub use timeouts::Timeouts;
+pub use transport::{
+    ListenAddr, Listener, MemoryConnector, MemoryListener, Transport,
+};
+pub use upstream::{Credentials, Upstream};
 
 #[cfg(test)]
 mod tests {
-    use log::{error, info};
-    use pretty_env_logger;
-    use tokio::task::JoinHandle;
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use log::info;
+    use tokio::{
+        io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        time::timeout,
+    };
 
     use crate::{
-        FilterResult::{Allow, Deny},
-        Server,
+        Addr, AuditRecord, CachingResolver, Client, Cmd, ConnectionContext,
+        ConnectionLimits, Credentials, Egress, Filter, FilterResult,
+        FilterResult::{Allow, Deny, LimitClass},
+        HostPeeking, Identity, IpNet, Limits, ListenAddr, Listener,
+        MemoryListener, Phase, Rate, RateLimits, Server, StaticHosts,
+        SystemResolver, Timeouts, Transport, Upstream, Verdict, WhenFull,
+        codec::{self, Encode as _},
         error::Error,
+        filter::BoxFuture,
+        http,
+        peek::tests::client_hello,
+        udp::UdpHeader,
     };
 
     fn setup_logger() {
-        pretty_env_logger::init();
+        let _ = pretty_env_logger::try_init();
+    }
+
+    /// A server which may reach the loopback listeners the tests start.
+    async fn local_server() -> Server {
+        let mut s = Server::new().await.unwrap();
+        s.allow_private_network("127.0.0.0/8".parse().unwrap(), None);
+        s
+    }
+
+    /// Starts a listener which echoes back whatever it receives.
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Runs the no-auth handshake and a CONNECT to `target`, returning the
+    /// reply code.
+    async fn connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+        connect_to(proxy, Addr::from_ip_addr(target.ip(), target.port())).await
+    }
+
+    /// Like [`connect`] for any kind of destination.
+    async fn connect_to(proxy: SocketAddr, target: Addr) -> (TcpStream, u8) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        request_to(stream, target).await
+    }
+
+    /// Logs in with a username and password, returning the stream if the
+    /// server accepted them.
+    async fn login(
+        proxy: SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> Option<TcpStream> {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x02]);
+
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend(password.as_bytes());
+        stream.write_all(&auth).await.unwrap();
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await.unwrap();
+        (status == [0x01, 0x00]).then_some(stream)
+    }
+
+    /// Sends a CONNECT to `target` on an authenticated stream, returning the
+    /// reply code.
+    async fn request(stream: TcpStream, target: SocketAddr) -> (TcpStream, u8) {
+        request_to(stream, Addr::from_ip_addr(target.ip(), target.port())).await
+    }
+
+    async fn request_to<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        target: Addr,
+    ) -> (S, u8) {
+        let req = codec::Request {
+            cmd: Cmd::Connect,
+            addr: target,
+        };
+        stream.write_all(&req.to_vec()).await.unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            _ => 0,
+        };
+        let mut bnd = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bnd).await.unwrap();
+        (stream, reply[1])
+    }
+
+    #[tokio::test]
+    async fn spawned_server_proxies_until_shutdown() {
+        setup_logger();
+        let target = echo_server().await;
+        let handle = local_server().await.spawn();
+
+        let (mut stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let proxy = handle.addr();
+        handle.shutdown(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(proxy).await.is_err());
+        // the echo connection never finishes on its own so it gets aborted
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn pipelined_handshakes_keep_what_follows() {
+        setup_logger();
+        let target = echo_server().await;
+        let handle = local_server().await.spawn();
+
+        // greeting, request and the first payload all in one go
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut sent = vec![0x05, 0x01, 0x00];
+        let req = codec::Request {
+            cmd: Cmd::Connect,
+            addr: Addr::from_ip_addr(target.ip(), target.port()),
+        };
+        sent.extend(req.to_vec());
+        sent.extend(b"ping");
+        stream.write_all(&sent).await.unwrap();
+
+        let mut replies = [0u8; 2 + 10];
+        stream.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[..4], [0x05, 0x00, 0x05, 0x00]);
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn denied_requests_get_ruleset_reply() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.add_filter(|_| Deny);
+        let handle = s.spawn();
+
+        let (_stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x02);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn private_network_guard_is_on_by_default() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = Server::new().await.unwrap();
+        s.allow_private_network(IpNet::from(target.ip()), Some(1));
+        let handle = s.spawn();
+
+        let (_stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x02);
+
+        // domains are checked by what they resolve to
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        let mut req = vec![0x05, 0x01, 0x00, 0x03, 9];
+        req.extend(b"localhost");
+        req.extend(target.port().to_be_bytes());
+        stream.write_all(&req).await.unwrap();
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x02);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn username_password_auth_reaches_filters() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.set_authenticator(|user, pass| user == "doc-1" && pass == "token");
+        s.add_filter(|ctx| match ctx.identity.username() {
+            Some("doc-1") => Allow,
+            _ => Deny,
+        });
+        let handle = s.spawn();
+
+        assert!(login(handle.addr(), "doc-1", "wrong").await.is_none());
+        let stream = login(handle.addr(), "doc-1", "token").await.unwrap();
+        let (_stream, reply) = request(stream, target).await;
+        assert_eq!(reply, 0x00);
+
+        // clients which only offer NO AUTHENTICATION REQUIRED are turned away
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0xFF]);
+        handle.abort().await;
+    }
+
+    /// Reads a reply with an IPv4 BND.ADDR.
+    async fn read_v4_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], 0x01);
+        let addr = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+        (reply[1], addr)
+    }
+
+    /// Starts a UDP socket which echoes back whatever it receives.
+    async fn udp_echo_server() -> SocketAddr {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+        echo_addr
+    }
+
+    /// Sets up a UDP association, returning its control connection and the
+    /// relay's address.
+    async fn associate(proxy: SocketAddr) -> (TcpStream, SocketAddr) {
+        let mut control = TcpStream::connect(proxy).await.unwrap();
+        control.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        control.read_exact(&mut method).await.unwrap();
+        control
+            .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let (reply, relay) = read_v4_reply(&mut control).await;
+        assert_eq!(reply, 0x00);
+        (control, relay)
+    }
+
+    #[tokio::test]
+    async fn udp_associate_relays_allowed_datagrams() {
+        setup_logger();
+        let echo_addr = udp_echo_server().await;
+        let blocked_port = echo_addr.port().wrapping_add(1);
+        let mut s = local_server().await;
+        s.add_filter(move |ctx| match ctx.addr {
+            Addr::Ip(_, port) if port == blocked_port => Deny,
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (control, relay) = associate(handle.addr()).await;
+
+        let send = |port: u16, payload: &'static [u8]| {
+            let header =
+                UdpHeader::new(Addr::from_ip_addr(echo_addr.ip(), port));
+            let client = &client;
+            async move {
+                let datagram = header.encapsulate(payload);
+                client.send_to(&datagram, relay).await.unwrap();
+            }
+        };
+        send(blocked_port, b"blocked").await;
+        send(echo_addr.port(), b"allowed").await;
+        let mut buf = [0u8; 1024];
+        let (len, _) =
+            timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        let (header, payload) = UdpHeader::parse(&buf[..len]).unwrap();
+        assert!(
+            matches!(header.addr, Addr::Ip(_, port) if port == echo_addr.port())
+        );
+        assert_eq!(payload, b"allowed");
+
+        // closing the control connection ends the association
+        drop(control);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        send(echo_addr.port(), b"late").await;
+        assert!(
+            timeout(Duration::from_millis(200), client.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        handle.abort().await;
+    }
+
+    /// Never decides on `stalled`, allows everything else and counts how
+    /// often it was asked.
+    struct StalledFilter {
+        stalled: u16,
+        checks: Arc<AtomicUsize>,
+    }
+
+    impl Filter for StalledFilter {
+        fn check<'a>(
+            &'a self,
+            ctx: &'a ConnectionContext,
+        ) -> BoxFuture<'a, FilterResult> {
+            Box::pin(async move {
+                match ctx.addr {
+                    Addr::Ip(_, port) if port == self.stalled => {
+                        std::future::pending().await
+                    }
+                    _ => {
+                        self.checks.fetch_add(1, Ordering::SeqCst);
+                        Allow
+                    }
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_decisions_are_cached_and_do_not_block_the_relay() {
+        setup_logger();
+        let echo_addr = udp_echo_server().await;
+        let checks = Arc::new(AtomicUsize::new(0));
+        let mut s = local_server().await;
+        s.add_async_filter(StalledFilter {
+            stalled: echo_addr.port().wrapping_add(1),
+            checks: checks.clone(),
+        });
+        let handle = s.spawn();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_control, relay) = associate(handle.addr()).await;
+
+        let mut buf = [0u8; 1024];
+        for (port, payload) in [
+            (echo_addr.port().wrapping_add(1), &b"stalled"[..]),
+            (echo_addr.port(), b"first"),
+            (echo_addr.port(), b"second"),
+        ] {
+            let header =
+                UdpHeader::new(Addr::from_ip_addr(echo_addr.ip(), port));
+            let datagram = header.encapsulate(payload);
+            client.send_to(&datagram, relay).await.unwrap();
+        }
+        for expected in [&b"first"[..], b"second"] {
+            let (len, _) =
+                timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let (_, payload) = UdpHeader::parse(&buf[..len]).unwrap();
+            assert_eq!(payload, expected);
+        }
+        // the second datagram went out on the first one's decision
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+        handle.abort().await;
+    }
+
+    /// Allows everything once it is opened.
+    struct GatedFilter(Arc<tokio::sync::Notify>);
+
+    impl Filter for GatedFilter {
+        fn check<'a>(
+            &'a self,
+            _: &'a ConnectionContext,
+        ) -> BoxFuture<'a, FilterResult> {
+            Box::pin(async move {
+                self.0.notified().await;
+                Allow
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn held_datagrams_respect_the_kill_switch() {
+        setup_logger();
+        let echo_addr = udp_echo_server().await;
+        let gate = Arc::new(tokio::sync::Notify::new());
+        let mut s = local_server().await;
+        s.add_async_filter(GatedFilter(gate.clone()));
+        let server = s.clone();
+        let handle = s.spawn();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_control, relay) = associate(handle.addr()).await;
+        let header = UdpHeader::new(Addr::from_ip_addr(
+            echo_addr.ip(),
+            echo_addr.port(),
+        ));
+        let datagram = header.encapsulate(b"held");
+        client.send_to(&datagram, relay).await.unwrap();
+        // the association stays up, its held destination is cut off
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.set_egress(Egress::AllowList(vec!["example.com".into()]));
+        gate.notify_one();
+        let mut buf = [0u8; 1024];
+        let echoed =
+            timeout(Duration::from_millis(200), client.recv_from(&mut buf));
+        assert!(echoed.await.is_err());
+
+        // what the kill switch refused is decided on again once it allows it
+        server.set_egress(Egress::On);
+        gate.notify_one();
+        client.send_to(&datagram, relay).await.unwrap();
+        let (len, _) =
+            timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(UdpHeader::parse(&buf[..len]).unwrap().1, b"held");
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn bind_accepts_one_filtered_peer() {
+        setup_logger();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut s = local_server().await;
+        let seen_by_filter = seen.clone();
+        s.add_filter(move |ctx| {
+            let seen = format!("{} {:?}", ctx.cmd, ctx.addr);
+            seen_by_filter.lock().unwrap().push(seen);
+            Allow
+        });
+        let handle = s.spawn();
+
+        let mut control = TcpStream::connect(handle.addr()).await.unwrap();
+        control.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        control.read_exact(&mut method).await.unwrap();
+        control
+            .write_all(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let (reply, listening) = read_v4_reply(&mut control).await;
+        assert_eq!(reply, 0x00);
+
+        let mut peer = TcpStream::connect(listening).await.unwrap();
+        let (reply, peer_addr) = read_v4_reply(&mut control).await;
+        assert_eq!(reply, 0x00);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        control.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(seen.len(), 2);
+        assert!(seen[1].contains(&peer_addr.port().to_string()));
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn binds_give_up_after_their_timeout() {
+        setup_logger();
+        let mut s = local_server().await;
+        s.set_timeouts(Timeouts {
+            bind: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let handle = s.spawn();
+
+        let mut control = TcpStream::connect(handle.addr()).await.unwrap();
+        control.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        control.read_exact(&mut method).await.unwrap();
+        control
+            .write_all(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let (reply, _) = read_v4_reply(&mut control).await;
+        assert_eq!(reply, 0x00);
+        // nobody connects
+        let (reply, _) =
+            timeout(Duration::from_secs(5), read_v4_reply(&mut control))
+                .await
+                .unwrap();
+        assert_eq!(reply, 0x06);
+        handle.abort().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_handshakes_do_not_block_other_clients() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<Server>();
+
+        setup_logger();
+        let target = echo_server().await;
+        let handle = local_server().await.spawn();
+
+        // connects but never sends its greeting
+        let _stalled = TcpStream::connect(handle.addr()).await.unwrap();
+        let (_stream, reply) =
+            timeout(Duration::from_secs(5), connect(handle.addr(), target))
+                .await
+                .expect("second client to be served");
+        assert_eq!(reply, 0x00);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn filters_can_redirect_deny_with_and_limit() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.add_filter(move |ctx| match ctx.addr.port() {
+            1 => FilterResult::Redirect(Addr::from_ip_addr(
+                target.ip(),
+                target.port(),
+            )),
+            2 => FilterResult::DenyWith(Error::ConnectionRefused),
+            _ => Allow,
+        });
+        s.add_filter(move |ctx| {
+            // later filters see the redirected and resolved destination
+            assert_eq!(ctx.resolved, [target.ip()]);
+            FilterResult::AllowWithLimits(Limits {
+                max_lifetime: Some(Duration::from_millis(100)),
+                ..Limits::default()
+            })
+        });
+        let handle = s.spawn();
+
+        let (_stream, reply) =
+            connect(handle.addr(), SocketAddr::from(([127, 0, 0, 1], 2))).await;
+        assert_eq!(reply, 0x05);
+
+        let (mut stream, reply) =
+            connect(handle.addr(), SocketAddr::from(([127, 0, 0, 1], 1))).await;
+        assert_eq!(reply, 0x00);
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        // the lifetime limit closes the connection
+        let closed = timeout(Duration::from_secs(5), stream.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        handle.abort().await;
+    }
+
+    /// Allows a connection only after it has waited a little.
+    struct SlowFilter;
+
+    impl Filter for SlowFilter {
+        fn check<'a>(
+            &'a self,
+            ctx: &'a ConnectionContext,
+        ) -> BoxFuture<'a, FilterResult> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                match ctx.cmd {
+                    Cmd::Connect => Allow,
+                    _ => Deny,
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn async_filters_are_awaited() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.add_async_filter(SlowFilter);
+        let handle = s.spawn();
+
+        let (_stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        handle.abort().await;
+    }
+
+    /// Writes `ping` and waits for the echo.
+    async fn ping<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_resets_on_traffic_and_filters_override_it() {
+        setup_logger();
+        let target = echo_server().await;
+        let patient = echo_server().await;
+        let mut s = local_server().await;
+        s.set_timeouts(Timeouts {
+            idle: Some(Duration::from_millis(300)),
+            ..Timeouts::default()
+        });
+        s.add_filter(move |ctx| match ctx.addr.port() {
+            port if port == patient.port() => {
+                FilterResult::AllowWithLimits(Limits {
+                    idle_timeout: Some(Duration::from_secs(30)),
+                    ..Limits::default()
+                })
+            }
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        let (mut busy, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        let (mut quiet, reply) = connect(handle.addr(), patient).await;
+        assert_eq!(reply, 0x00);
+        // outlives the idle timeout several times over while staying busy
+        for _ in 0..8 {
+            ping(&mut busy).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        ping(&mut quiet).await;
+
+        let mut buf = [0u8; 4];
+        let closed = timeout(Duration::from_secs(5), busy.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_time_out() {
+        setup_logger();
+        let mut s = local_server().await;
+        s.set_timeouts(Timeouts {
+            handshake: Duration::from_millis(100),
+            ..Timeouts::default()
+        });
+        let handle = s.spawn();
+
+        let mut stalled = TcpStream::connect(handle.addr()).await.unwrap();
+        stalled.write_all(&[0x05, 0x01]).await.unwrap();
+        let mut buf = [0u8; 2];
+        let closed = timeout(Duration::from_secs(5), stalled.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn metrics_count_traffic_per_host_and_identity() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.set_authenticator(|_, pass| pass == "token");
+        s.add_filter(move |ctx| match ctx.identity.username() {
+            Some("blocked") => Deny,
+            _ => Allow,
+        });
+        let (reports, mut reported) = tokio::sync::mpsc::unbounded_channel();
+        s.on_metrics(Duration::from_millis(10), move |snapshot| {
+            let _ = reports.send(snapshot.total);
+        });
+        let server = s.clone();
+        let handle = s.spawn();
+
+        let stream = login(handle.addr(), "doc-1", "token").await.unwrap();
+        let (mut stream, reply) = request(stream, target).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        let stream = login(handle.addr(), "blocked", "token").await.unwrap();
+        let (_denied, reply) = request(stream, target).await;
+        assert_eq!(reply, 0x02);
+
+        let metrics = server.metrics();
+        let host = &metrics.hosts[&target.ip().to_string()];
+        assert_eq!((host.accepted, host.denied), (1, 1));
+        assert_eq!((host.bytes_up, host.bytes_down), (4, 4));
+        let user = &metrics.identities[&Identity::User("doc-1".into())];
+        assert_eq!((user.active, user.accepted, user.denied), (1, 1, 0));
+        assert_eq!(metrics.denied_by_reason["ruleset"], 1);
+
+        // reports keep coming until one has caught up with the traffic
+        let caught_up = timeout(Duration::from_secs(5), async {
+            while reported.recv().await.unwrap().bytes_down < 4 {}
+        });
+        caught_up.await.unwrap();
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn resolver_pins_domains_to_local_servers() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("app.test", [target.ip()]);
+        hosts.insert("blocked.test", []);
+        let mut s = local_server().await;
+        s.set_resolver(CachingResolver::new(hosts));
+        let handle = s.spawn();
+
+        let app = Addr::Domain("app.test".into(), target.port());
+        let (mut stream, reply) = connect_to(handle.addr(), app).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+
+        let blocked = Addr::Domain("blocked.test".into(), target.port());
+        let (_stream, reply) = connect_to(handle.addr(), blocked).await;
+        assert_eq!(reply, 0x04);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn unreachable_first_address_does_not_stall_connect() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        // TEST-NET-1 is never routed, connecting to it hangs or fails
+        hosts.insert("flaky.test", ["192.0.2.1".parse().unwrap(), target.ip()]);
+        let mut s = local_server().await;
+        s.set_resolver(hosts);
+        let handle = s.spawn();
+
+        let flaky = Addr::Domain("flaky.test".into(), target.port());
+        let connected =
+            timeout(Duration::from_secs(5), connect_to(handle.addr(), flaky));
+        let (mut stream, reply) =
+            connected.await.expect("connect to not stall");
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn socks5_upstream_gets_domains_unresolved() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("app.test", [target.ip()]);
+        let seen_upstream = Arc::new(Mutex::new(Vec::new()));
+        let mut upstream = local_server().await;
+        upstream.set_resolver(hosts);
+        upstream
+            .set_authenticator(|user, pass| user == "chain" && pass == "pw");
+        let seen = seen_upstream.clone();
+        upstream.add_filter(move |ctx| {
+            seen.lock().unwrap().push(ctx.addr.clone());
+            Allow
+        });
+        let upstream = upstream.spawn();
+
+        let mut s = local_server().await;
+        s.set_upstream(Upstream::Socks5 {
+            addr: Addr::from_ip_addr(upstream.addr().ip(), upstream.port()),
+            credentials: Some(Credentials {
+                username: "chain".into(),
+                password: "pw".into(),
+            }),
+        });
+        // filters still run locally, without anything resolved
+        s.add_filter(|ctx| match ctx.resolved.is_empty() {
+            true => Allow,
+            false => Deny,
+        });
+        let handle = s.spawn();
+
+        let app = Addr::Domain("app.test".into(), target.port());
+        let (mut stream, reply) = connect_to(handle.addr(), app.clone()).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        assert_eq!(*seen_upstream.lock().unwrap(), [app]);
+        handle.abort().await;
+        upstream.abort().await;
+    }
+
+    /// Accepts a single `CONNECT` tunnel like an HTTP proxy would, always to
+    /// `target`, and returns the request line it got.
+    async fn http_proxy(
+        target: SocketAddr,
+    ) -> (SocketAddr, Arc<Mutex<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request_line = Arc::new(Mutex::new(String::new()));
+        let seen = request_line.clone();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+            *seen.lock().unwrap() = head.lines().next().unwrap().to_string();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            let mut remote = TcpStream::connect(target).await.unwrap();
+            let _ =
+                tokio::io::copy_bidirectional(&mut client, &mut remote).await;
+        });
+        (addr, request_line)
+    }
+
+    #[tokio::test]
+    async fn filters_can_route_through_an_http_proxy() {
+        setup_logger();
+        let target = echo_server().await;
+        let (proxy, request_line) = http_proxy(target).await;
+        let mut s = local_server().await;
+        s.add_filter(move |ctx| match &ctx.addr {
+            Addr::Domain(domain, _) if domain == "intranet.test" => {
+                FilterResult::RouteVia(Upstream::HttpConnect {
+                    addr: Addr::from_ip_addr(proxy.ip(), proxy.port()),
+                })
+            }
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        // only the HTTP proxy knows this name
+        let intranet = Addr::Domain("intranet.test".into(), 8443);
+        let (mut stream, reply) = connect_to(handle.addr(), intranet).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        assert_eq!(
+            *request_line.lock().unwrap(),
+            "CONNECT intranet.test:8443 HTTP/1.1"
+        );
+        handle.abort().await;
+    }
+
+    /// Sends an HTTP proxy request and returns the status line of the
+    /// response, leaving the stream right after the response head.
+    async fn http_request(
+        proxy: SocketAddr,
+        head: &str,
+    ) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut res = Vec::new();
+        while !res.ends_with(b"\r\n\r\n") {
+            res.push(stream.read_u8().await.unwrap());
+        }
+        let res = String::from_utf8(res).unwrap();
+        (stream, res.lines().next().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn http_connect_shares_filters_and_auth() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.set_authenticator(|user, pass| user == "doc-1" && pass == "token");
+        s.add_filter(|ctx| match ctx.addr.port() {
+            1 => Deny,
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        let connect = |port: u16, auth: &str| {
+            format!(
+                "CONNECT 127.0.0.1:{port} HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\n{auth}\r\n"
+            )
+        };
+        let auth = "Proxy-Authorization: Basic ZG9jLTE6dG9rZW4=\r\n";
+        let (_stream, status) =
+            http_request(handle.addr(), &connect(target.port(), "")).await;
+        assert_eq!(status, "HTTP/1.1 407 Proxy Authentication Required");
+        let (_stream, status) =
+            http_request(handle.addr(), &connect(1, auth)).await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+        let (mut stream, status) =
+            http_request(handle.addr(), &connect(target.port(), auth)).await;
+        assert_eq!(status, "HTTP/1.1 200 Connection established");
+        ping(&mut stream).await;
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn absolute_form_requests_are_forwarded() {
+        setup_logger();
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi")
+                .await
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        let handle = local_server().await.spawn();
+
+        let (mut stream, status) = http_request(
+            handle.addr(),
+            &format!(
+                "GET http://{origin_addr}/page?q=1 HTTP/1.1\r\nHost: {origin_addr}\r\nProxy-Connection: keep-alive\r\n\r\n"
+            ),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let mut body = [0u8; 2];
+        stream.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"hi");
+        assert_eq!(
+            received.await.unwrap(),
+            format!(
+                "GET /page?q=1 HTTP/1.1\r\nHost: {origin_addr}\r\nConnection: close\r\n\r\n"
+            )
+        );
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn socks4a_shares_filters_and_resolver() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("app.test", [target.ip()]);
+        let mut s = local_server().await;
+        s.set_resolver(hosts);
+        s.add_filter(|ctx| match &ctx.addr {
+            Addr::Domain(domain, _) if domain == "blocked.test" => Deny,
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        let socks4 = |ip: [u8; 4], domain: &str| {
+            let mut req = vec![0x04, 0x01];
+            req.extend(target.port().to_be_bytes());
+            req.extend(ip);
+            req.extend(b"user\0");
+            if !domain.is_empty() {
+                req.extend(domain.as_bytes());
+                req.push(0);
+            }
+            req
+        };
+        let mut reply = [0u8; 8];
+
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        stream
+            .write_all(&socks4([0, 0, 0, 1], "app.test"))
+            .await
+            .unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 0x5A]);
+        ping(&mut stream).await;
+
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let ip = match target.ip() {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => unreachable!("echo server is on 127.0.0.1"),
+        };
+        stream.write_all(&socks4(ip, "")).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 0x5A]);
+        ping(&mut stream).await;
+
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let req = socks4([0, 0, 0, 1], "blocked.test");
+        stream.write_all(&req).await.unwrap();
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x00, 0x5B, 0, 0, 0, 0, 0, 0]);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn filters_put_connections_in_rate_limit_classes() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("tracker.test", [target.ip()]);
+        let mut s = local_server().await;
+        s.set_resolver(hosts);
+        s.set_rate_limits(RateLimits {
+            classes: [(
+                "trackers".to_string(),
+                Rate {
+                    connections: Some(1),
+                    ..Rate::default()
+                },
+            )]
+            .into(),
+            ..RateLimits::default()
+        });
+        s.add_filter(|ctx| match &ctx.addr {
+            Addr::Domain(..) => LimitClass("trackers".into()),
+            _ => Allow,
+        });
+        let server = s.clone();
+        let handle = s.spawn();
+
+        let tracker = Addr::Domain("tracker.test".into(), target.port());
+        let (mut stream, reply) =
+            connect_to(handle.addr(), tracker.clone()).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        let (_stream, reply) = connect_to(handle.addr(), tracker).await;
+        assert_eq!(reply, 0x02);
+        // connections outside the class are unaffected
+        let (mut stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        assert_eq!(server.metrics().denied_by_reason["rate_limited"], 1);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn connection_caps_queue_then_refuse() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.set_connection_limits(ConnectionLimits {
+            per_host: Some(1),
+            when_full: WhenFull::Wait {
+                max_waiting: 1,
+                timeout: Duration::from_secs(5),
+            },
+            ..ConnectionLimits::default()
+        });
+        let handle = s.spawn();
+
+        let (mut open, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut open).await;
+        let queued = tokio::spawn(connect(handle.addr(), target));
+        // give the queued request time to start waiting
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (_refused, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x01);
+
+        drop(open);
+        let (mut stream, reply) = queued.await.unwrap();
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn every_attempt_is_audited() {
+        setup_logger();
+        let allowed = echo_server().await;
+        let blocked = echo_server().await;
+        let mut s = local_server().await;
+        s.add_filter(move |ctx: &ConnectionContext| match ctx.target() {
+            Ok(target) if target == blocked => Deny,
+            _ => Allow,
+        });
+        let (records, mut recorded) = tokio::sync::mpsc::unbounded_channel();
+        s.add_audit_sink(move |record: &AuditRecord| {
+            let _ = records.send(record.clone());
+        });
+        let handle = s.spawn();
+
+        let (_denied, reply) = connect(handle.addr(), blocked).await;
+        assert_eq!(reply, 0x02);
+        let denied = recorded.recv().await.unwrap();
+        assert_eq!(denied.decision, Verdict::Denied);
+        assert_eq!(denied.cmd, Some(Cmd::Connect));
+        assert_eq!(
+            denied.target,
+            Some(Addr::from_ip_addr(blocked.ip(), blocked.port()))
+        );
+        assert_eq!(denied.reason, Some("ruleset"));
+        assert!(denied.filter.unwrap().contains("every_attempt_is_audited"));
+        assert_eq!(denied.reply, Some(0x02));
+
+        let (mut stream, reply) = connect(handle.addr(), allowed).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+        // allowed connections are recorded when they open and close
+        let opened = recorded.recv().await.unwrap();
+        assert_eq!(opened.decision, Verdict::Allowed);
+        assert_eq!(opened.phase, Phase::Opened);
+        assert_eq!(opened.resolved, Some(allowed.ip()));
+        assert_eq!((opened.bytes_up, opened.bytes_down), (0, 0));
+        assert!(recorded.try_recv().is_err());
+        drop(stream);
+        let record = timeout(Duration::from_secs(5), recorded.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.decision, Verdict::Allowed);
+        assert_eq!(record.phase, Phase::Closed);
+        assert_eq!(record.identity, Some(Identity::Anonymous));
+        assert_eq!(record.resolved, Some(allowed.ip()));
+        assert_eq!(record.reply, Some(0x00));
+        assert_eq!((record.bytes_up, record.bytes_down), (4, 4));
+
+        // clients which never get as far as a request are recorded too
+        let mut garbage = TcpStream::connect(handle.addr()).await.unwrap();
+        garbage.write_all(&[0x01]).await.unwrap();
+        let failed = recorded.recv().await.unwrap();
+        assert_eq!((failed.cmd, failed.reply), (None, None));
+        assert_eq!(failed.reason, Some("version_mismatch"));
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn kill_switch_closes_running_connections() {
+        setup_logger();
+        let target = echo_server().await;
+        let s = local_server().await;
+        let server = s.clone();
+        let handle = s.spawn();
+
+        let (mut open, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut open).await;
+        server.set_egress(Egress::Off);
+        let mut buf = [0u8; 1];
+        let closed = timeout(Duration::from_secs(5), open.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        let (_refused, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x02);
+
+        // the allow list covers the echo server, only offline apps are cut
+        let allowed = Egress::AllowList(vec![target.ip().to_string()]);
+        server.set_egress(allowed);
+        let (mut open, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut open).await;
+        server.set_offline(&Identity::Anonymous, true);
+        let closed = timeout(Duration::from_secs(5), open.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        assert_eq!(connect(handle.addr(), target).await.1, 0x02);
+        server.set_offline(&Identity::Anonymous, false);
+        assert_eq!(connect(handle.addr(), target).await.1, 0x00);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn peeked_hosts_are_filtered_and_replayed() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("allowed.test", [target.ip()]);
+        hosts.insert("blocked.test", [target.ip()]);
+        let mut s = local_server().await;
+        s.set_resolver(hosts);
+        s.set_host_peeking(HostPeeking::Required);
+        s.add_filter(|ctx| match &ctx.addr {
+            Addr::Domain(domain, _) if domain == "blocked.test" => Deny,
+            _ => Allow,
+        });
+        let handle = s.spawn();
+
+        let hello = client_hello("allowed.test");
+        let (mut stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        stream.write_all(&hello).await.unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, hello);
+        ping(&mut stream).await;
+
+        // refused after the early reply, the connection just closes
+        let refused =
+            [client_hello("blocked.test"), b"\x00 names no host".to_vec()];
+        for first in refused {
+            let (mut stream, reply) = connect(handle.addr(), target).await;
+            assert_eq!(reply, 0x00);
+            stream.write_all(&first).await.unwrap();
+            let mut rest = Vec::new();
+            let closed =
+                timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+            assert_eq!(closed.await.unwrap().unwrap(), 0);
+        }
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn peek_previews_leave_the_decision_to_the_last_pass() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut hosts = StaticHosts::new(SystemResolver);
+        hosts.insert("allowed.test", [target.ip()]);
+        let passes = Arc::new(Mutex::new(Vec::new()));
+        let mut s = local_server().await;
+        s.set_resolver(hosts);
+        s.set_host_peeking(HostPeeking::On);
+        s.add_filter({
+            let passes = passes.clone();
+            // asks about hosts only, like the app's prompts
+            move |ctx| {
+                passes
+                    .lock()
+                    .unwrap()
+                    .push((ctx.addr.clone(), ctx.will_peek));
+                match (&ctx.addr, ctx.will_peek) {
+                    (Addr::Ip(..), false) => Deny,
+                    _ => Allow,
+                }
+            }
+        });
+        let handle = s.spawn();
+        let ip = Addr::from_ip_addr(target.ip(), target.port());
+
+        let hello = client_hello("allowed.test");
+        let (mut stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        stream.write_all(&hello).await.unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        let domain = Addr::Domain("allowed.test".into(), target.port());
+        assert_eq!(
+            passes.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [(ip.clone(), true), (domain, false)]
+        );
+
+        // naming no host, the IP itself is decided on after all
+        let (mut stream, reply) = connect(handle.addr(), target).await;
+        assert_eq!(reply, 0x00);
+        stream.write_all(b"\x00 names no host").await.unwrap();
+        let mut rest = Vec::new();
+        let closed =
+            timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        assert_eq!(*passes.lock().unwrap(), [(ip.clone(), true), (ip, false)]);
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn memory_clients_run_the_full_handshake() {
+        setup_logger();
+        let target = echo_server().await;
+        let (listener, connector) = MemoryListener::new();
+        let mut s = Server::builder().listener(listener).build().await.unwrap();
+        s.allow_private_network("127.0.0.0/8".parse().unwrap(), None);
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let seen = peers.clone();
+        s.add_filter(move |ctx| {
+            seen.lock().unwrap().push(ctx.peer);
+            Allow
+        });
+        assert_eq!(s.listen_addrs(), [ListenAddr::Memory]);
+        let handle = s.spawn();
+
+        let mut stream = connector.connect().await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        let addr = Addr::from_ip_addr(target.ip(), target.port());
+        let (mut stream, reply) = request_to(stream, addr).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+
+        // the byte told apart protocols by is read again by HTTP as well
+        let mut stream = connector.connect().await.unwrap();
+        let head = format!("CONNECT {target} HTTP/1.1\r\n\r\n");
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut res = vec![0u8; http::ESTABLISHED.len()];
+        stream.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, http::ESTABLISHED);
+        ping(&mut stream).await;
+
+        // without an address the client shows up as unspecified
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        assert_eq!(*peers.lock().unwrap(), [unspecified, unspecified]);
+        handle.abort().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_only_let_the_user_in() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        setup_logger();
+        let target = echo_server().await;
+        let path = std::env::temp_dir()
+            .join(format!("socks5-test-{}.sock", std::process::id()));
+        let mut s = Server::builder().unix(&path, 0o600).build().await.unwrap();
+        s.allow_private_network("127.0.0.0/8".parse().unwrap(), None);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let handle = s.spawn();
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        let addr = Addr::from_ip_addr(target.ip(), target.port());
+        let (mut stream, reply) = request_to(stream, addr).await;
+        assert_eq!(reply, 0x00);
+        ping(&mut stream).await;
+
+        handle.abort().await;
+        assert!(!path.exists());
     }
 
     #[tokio::test]
+    async fn servers_listen_on_every_address_given() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = Server::builder()
+            .tcp("[::1]:0".parse().unwrap())
+            .tcp("127.0.0.1:0".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        s.allow_private_network("127.0.0.0/8".parse().unwrap(), None);
+        let addrs = s.listen_addrs();
+        let handle = s.spawn();
+
+        assert!(handle.addr().is_ipv6());
+        for addr in addrs {
+            let ListenAddr::Tcp(addr) = addr else {
+                panic!("{addr:?} is no TCP address");
+            };
+            let (mut stream, reply) = connect(addr, target).await;
+            assert_eq!(reply, 0x00);
+            ping(&mut stream).await;
+        }
+        handle.abort().await;
+    }
+
+    /// Fails every accept like a process out of file descriptors would.
+    struct Exhausted(Arc<AtomicUsize>);
+
+    impl Listener for Exhausted {
+        fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Transport>>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Err(io::Error::other("too many open files")) })
+        }
+
+        fn listen_addr(&self) -> ListenAddr {
+            ListenAddr::Memory
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_accepts_back_off() {
+        setup_logger();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let s = Server::builder()
+            .listener(Exhausted(accepts.clone()))
+            .build()
+            .await
+            .unwrap();
+
+        let stop = tokio::time::sleep(Duration::from_millis(200));
+        let connections = s.serve(stop).await;
+        assert!(connections.is_empty());
+        // 5, 10, 20, 40 and 80ms apart, rather than a hot loop
+        let accepts = accepts.load(Ordering::Relaxed);
+        assert!((2..=10).contains(&accepts), "{accepts} accepts");
+    }
+
+    #[tokio::test]
+    async fn clients_get_every_reply_code_as_an_error() {
+        setup_logger();
+        let target = echo_server().await;
+        let mut s = local_server().await;
+        s.add_filter(|ctx| match ctx.addr.host().as_deref() {
+            Some("ruleset.test") => Deny,
+            Some("general.test") => {
+                FilterResult::DenyWith(Error::Internal("test"))
+            }
+            Some("network.test") => {
+                FilterResult::DenyWith(Error::NetworkUnreachable)
+            }
+            Some("host.test") => FilterResult::DenyWith(Error::HostUnreachable),
+            Some("refused.test") => {
+                FilterResult::DenyWith(Error::ConnectionRefused)
+            }
+            Some("ttl.test") => FilterResult::DenyWith(Error::TtlExpired),
+            Some("atyp.test") => {
+                FilterResult::DenyWith(Error::AddressTypeNotSupported)
+            }
+            _ => Allow,
+        });
+        let handle = s.spawn();
+        let client = Client::default();
+
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let addr = Addr::from_ip_addr(target.ip(), target.port());
+        let (mut stream, bound) = client.connect(stream, addr).await.unwrap();
+        assert!(matches!(bound, Addr::Ip(ip, _) if ip.is_loopback()));
+        ping(&mut stream).await;
+
+        let cases = [
+            (Addr::Domain("general.test".into(), 1), "internal"),
+            (Addr::Domain("ruleset.test".into(), 1), "ruleset"),
+            (
+                Addr::Domain("network.test".into(), 1),
+                "network_unreachable",
+            ),
+            (Addr::Domain("host.test".into(), 1), "host_unreachable"),
+            (Addr::Domain("refused.test".into(), 1), "connection_refused"),
+            (Addr::Domain("ttl.test".into(), 1), "ttl_expired"),
+            (
+                Addr::Domain("atyp.test".into(), 1),
+                "address_type_not_supported",
+            ),
+        ];
+        for (addr, reason) in cases {
+            let stream = TcpStream::connect(handle.addr()).await.unwrap();
+            let err = client.connect(stream, addr.clone()).await.unwrap_err();
+            assert_eq!(err.reason(), reason, "{addr:?}");
+        }
+        handle.abort().await;
+    }
+
+    #[tokio::test]
+    async fn clients_authenticate_bind_and_associate() {
+        setup_logger();
+        let mut s = local_server().await;
+        s.set_authenticator(|username, password| {
+            username == "alice" && password == "secret"
+        });
+        let handle = s.spawn();
+        let credentials = |password: &str| {
+            Client::new(Some(Credentials {
+                username: "alice".into(),
+                password: password.into(),
+            }))
+        };
+        let anywhere = Addr::from_ip_addr([0, 0, 0, 0].into(), 0);
+
+        let logins = [
+            (Client::default(), "invalid_auth"),
+            (credentials("wrong"), "auth_failed"),
+        ];
+        for (client, reason) in logins {
+            let stream = TcpStream::connect(handle.addr()).await.unwrap();
+            let err = client.bind(stream, anywhere.clone()).await.unwrap_err();
+            assert_eq!(err.reason(), reason);
+        }
+
+        let client = credentials("secret");
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let bind = client.bind(stream, anywhere).await.unwrap();
+        let Addr::Ip(ip, port) = *bind.addr() else {
+            panic!("BIND listens on {:?}", bind.addr());
+        };
+        let mut peer = TcpStream::connect((ip, port)).await.unwrap();
+        let (mut stream, peer_addr) = bind.accept().await.unwrap();
+        let local = peer.local_addr().unwrap();
+        assert_eq!(peer_addr, Addr::from_ip_addr(local.ip(), local.port()));
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let udp = client.udp_associate(stream).await.unwrap();
+        let target = Addr::from_ip_addr(echo_addr.ip(), echo_addr.port());
+        udp.send_to(b"ping", target.clone()).await.unwrap();
+        let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        echo.send_to(b"pong", from).await.unwrap();
+        let received = timeout(Duration::from_secs(5), udp.recv_from(&mut buf));
+        let (len, from) = received.await.unwrap().unwrap();
+        assert_eq!((&buf[..len], from), (&b"pong"[..], target));
+        handle.abort().await;
+    }
+
+    /// Runs until killed so a browser can be pointed at the printed address.
+    #[tokio::test]
+    #[ignore = "manual test, runs forever"]
     pub async fn server() -> Result<(), Error> {
         setup_logger();
         info!("starting server...");
         let mut s = Server::new().await?;
         info!("server now running at {}", s.addr());
-        s.add_filter(|addr| {
-            info!("Likely blocking request to {addr:?}");
-            match addr {
+        s.add_filter(|ctx| {
+            info!("Likely blocking request to {:?}", ctx.addr);
+            match &ctx.addr {
                 crate::Addr::Ip(_ip_addr, _) => Deny,
                 crate::Addr::Domain(domain, _) => {
                     if domain == "webrtc.github.io" {
@@ -50,15 +1566,7 @@ mod tests {
                 crate::Addr::Null => Deny,
             }
         });
-        let mut jhs = Vec::<JoinHandle<()>>::new();
-        loop {
-            jhs.push(match s.poll().await {
-                Ok(h) => h,
-                Err(err) => {
-                    error!("{err}");
-                    continue;
-                }
-            });
-        }
+        s.serve(std::future::pending()).await;
+        Ok(())
     }
 }
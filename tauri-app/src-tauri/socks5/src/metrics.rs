@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{Addr, Error, audit::Audit, auth::Identity};
+
+/// Called with a fresh snapshot every interval, see
+/// [`Server::on_metrics`](crate::Server::on_metrics).
+pub type MetricsCallback = dyn Fn(&MetricsSnapshot) + Send + Sync;
+
+/// Traffic of a group of connections at the time of a snapshot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Connections being relayed right now.
+    pub active: u64,
+    /// Requests which were let through.
+    pub accepted: u64,
+    /// Requests which failed or were refused.
+    pub denied: u64,
+    /// Bytes clients sent to their destinations.
+    pub bytes_up: u64,
+    /// Bytes destinations sent back to clients.
+    pub bytes_down: u64,
+}
+
+/// Everything the server counted since it was created.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub total: Stats,
+    /// Denied requests by [`Error::reason`].
+    pub denied_by_reason: HashMap<&'static str, u64>,
+    /// Keyed by the destination as the client asked for it, a domain or an
+    /// IP. UDP associations have no single destination and only count
+    /// towards the totals and their identity.
+    ///
+    /// Like `identities` it holds a few thousand entries at most, the ones
+    /// no connection in progress counts towards are dropped to make room.
+    pub hosts: HashMap<String, Stats>,
+    pub identities: HashMap<Identity, Stats>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    active: AtomicU64,
+    accepted: AtomicU64,
+    denied: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> Stats {
+        Stats {
+            active: self.active.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Upper bound on the hosts and identities counted separately, groups no
+/// connection in progress counts towards are dropped once it is reached.
+const MAX_TRACKED: usize = 4096;
+
+/// Live counters shared by every clone of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    total: Counters,
+    denied_by_reason: Mutex<HashMap<&'static str, u64>>,
+    hosts: Mutex<HashMap<String, Arc<Counters>>>,
+    identities: Mutex<HashMap<Identity, Arc<Counters>>>,
+}
+
+impl Metrics {
+    /// `None` when there's no room for another group, the request then
+    /// only counts towards the totals.
+    fn group<K: Eq + Hash>(
+        groups: &Mutex<HashMap<K, Arc<Counters>>>,
+        key: K,
+    ) -> Option<Arc<Counters>> {
+        let mut groups =
+            groups.lock().expect("metrics lock to not be poisoned");
+        if !groups.contains_key(&key) && groups.len() >= MAX_TRACKED {
+            // trackers hold on to the groups they count towards
+            groups.retain(|_, counters| Arc::strong_count(counters) > 1);
+            if groups.len() >= MAX_TRACKED {
+                return None;
+            }
+        }
+        Some(groups.entry(key).or_default().clone())
+    }
+
+    /// The counters a request from `identity` to `addr` adds to.
+    fn groups(
+        &self,
+        identity: Option<&Identity>,
+        addr: Option<&Addr>,
+    ) -> Vec<Arc<Counters>> {
+        let identity = identity.and_then(|identity| {
+            Self::group(&self.identities, identity.clone())
+        });
+        let host = addr
+            .and_then(Addr::host)
+            .and_then(|host| Self::group(&self.hosts, host));
+        identity.into_iter().chain(host).collect()
+    }
+
+    pub fn accept(&self, identity: &Identity, addr: Option<&Addr>) {
+        self.total.accepted.fetch_add(1, Ordering::Relaxed);
+        for group in self.groups(Some(identity), addr) {
+            group.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a refused request. Requests which fail during the handshake
+    /// have neither an identity nor a destination yet.
+    pub fn deny(
+        &self,
+        err: &Error,
+        identity: Option<&Identity>,
+        addr: Option<&Addr>,
+    ) {
+        self.total.denied.fetch_add(1, Ordering::Relaxed);
+        *self
+            .denied_by_reason
+            .lock()
+            .expect("metrics lock to not be poisoned")
+            .entry(err.reason())
+            .or_default() += 1;
+        for group in self.groups(identity, addr) {
+            group.denied.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a connection's traffic, and it as active from when it is
+    /// connected until the returned tracker is dropped.
+    pub fn track(
+        self: &Arc<Self>,
+        identity: &Identity,
+        addr: Option<&Addr>,
+    ) -> Tracker {
+        Tracker {
+            metrics: self.clone(),
+            groups: self.groups(Some(identity), addr),
+            own: Counters::default(),
+            audit: None,
+            connected: false,
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        fn stats<K: Clone + Eq + Hash>(
+            groups: &Mutex<HashMap<K, Arc<Counters>>>,
+        ) -> HashMap<K, Stats> {
+            let groups =
+                groups.lock().expect("metrics lock to not be poisoned");
+            groups.iter().map(|(k, c)| (k.clone(), c.stats())).collect()
+        }
+        MetricsSnapshot {
+            total: self.total.stats(),
+            denied_by_reason: self
+                .denied_by_reason
+                .lock()
+                .expect("metrics lock to not be poisoned")
+                .clone(),
+            hosts: stats(&self.hosts),
+            identities: stats(&self.identities),
+        }
+    }
+}
+
+/// Counts the traffic of one connection while it is relayed, and completes
+/// its audit record once it is dropped.
+pub(crate) struct Tracker {
+    metrics: Arc<Metrics>,
+    groups: Vec<Arc<Counters>>,
+    /// This connection's traffic alone.
+    own: Counters,
+    audit: Option<Audit>,
+    /// Connections which failed before they were relayed are audited as
+    /// denied by whoever handles the failure instead.
+    connected: bool,
+}
+
+impl Tracker {
+    fn each(&self, f: impl Fn(&Counters) -> u64) {
+        f(&self.metrics.total);
+        f(&self.own);
+        for group in &self.groups {
+            f(group);
+        }
+    }
+
+    /// Sends `audit` to the sinks along with the traffic once the connection
+    /// is over, if it ever got connected.
+    pub fn audit(&mut self, audit: Audit) {
+        self.audit = Some(audit);
+    }
+
+    /// Marks the connection as relayed, to `resolved` if it went to a single
+    /// address. The first time round it becomes active and is audited as
+    /// opened.
+    pub fn connected(&mut self, resolved: Option<IpAddr>) {
+        if let (Some(audit), Some(ip)) = (&mut self.audit, resolved) {
+            audit.set_resolved(ip);
+        }
+        if self.connected {
+            return;
+        }
+        self.each(|c| c.active.fetch_add(1, Ordering::Relaxed));
+        if let Some(audit) = &self.audit {
+            audit.opened();
+        }
+        self.connected = true;
+    }
+
+    pub fn up(&self, bytes: u64) {
+        self.each(|c| c.bytes_up.fetch_add(bytes, Ordering::Relaxed));
+    }
+
+    pub fn down(&self, bytes: u64) {
+        self.each(|c| c.bytes_down.fetch_add(bytes, Ordering::Relaxed));
+    }
+
+    /// Wraps the client's side of a connection: whatever is read from it went
+    /// up, whatever is written to it came down.
+    pub fn count<S>(&self, client: S) -> Counted<'_, S> {
+        Counted {
+            inner: client,
+            tracker: self,
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        if self.connected {
+            self.each(|c| c.active.fetch_sub(1, Ordering::Relaxed));
+        }
+        if let Some(audit) = self.audit.take().filter(|_| self.connected) {
+            let Stats {
+                bytes_up,
+                bytes_down,
+                ..
+            } = self.own.stats();
+            audit.finish(bytes_up, bytes_down);
+        }
+    }
+}
+
+pub(crate) struct Counted<'a, S> {
+    inner: S,
+    tracker: &'a Tracker,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.tracker.up((buf.filled().len() - before) as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.tracker.down(written as u64);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    use super::{MAX_TRACKED, Metrics};
+    use crate::{Addr, Identity, error::Error};
+
+    #[tokio::test]
+    async fn counts_per_host_and_identity() {
+        let metrics = Arc::new(Metrics::default());
+        let user = Identity::User("doc-1".into());
+        let host = Addr::Domain("example.com".into(), 443);
+
+        let mut tracker = metrics.track(&user, Some(&host));
+        metrics.accept(&user, Some(&host));
+        // filtered and waiting connections aren't active yet
+        assert_eq!(metrics.snapshot().total.active, 0);
+        tracker.connected(None);
+        // the proxy's end of the client's connection
+        let (mut app, client) = duplex(64);
+        let mut client = tracker.count(client);
+        app.write_all(b"hello").await.unwrap();
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        app.read_exact(&mut buf[..2]).await.unwrap();
+
+        metrics.deny(&Error::BreaksRuleset, Some(&Identity::Anonymous), None);
+        metrics.deny(&Error::TtlExpired, None, None);
+
+        let snapshot = metrics.snapshot();
+        let stats = snapshot.hosts["example.com"];
+        assert_eq!((stats.active, stats.bytes_up, stats.bytes_down), (1, 5, 2));
+        assert_eq!(snapshot.identities[&user], stats);
+        assert_eq!(snapshot.total.denied, 2);
+        assert_eq!(snapshot.identities[&Identity::Anonymous].denied, 1);
+        assert_eq!(snapshot.denied_by_reason["ruleset"], 1);
+
+        drop(client);
+        drop(tracker);
+        assert_eq!(metrics.snapshot().total.active, 0);
+    }
+
+    #[test]
+    fn idle_groups_make_room() {
+        let metrics = Arc::new(Metrics::default());
+        let user = Identity::User("doc-1".into());
+        let busy = Addr::Domain("busy.test".into(), 443);
+        let mut tracker = metrics.track(&user, Some(&busy));
+        tracker.connected(None);
+
+        // a page denied on a fresh subdomain every time
+        for n in 0..2 * MAX_TRACKED {
+            let host = Addr::Domain(format!("{n}.tracker.test"), 443);
+            metrics.deny(&Error::BreaksRuleset, Some(&user), Some(&host));
+        }
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.hosts.len() <= MAX_TRACKED);
+        assert_eq!(snapshot.hosts["busy.test"].active, 1);
+        assert_eq!(snapshot.identities[&user].denied, 2 * MAX_TRACKED as u64);
+        assert_eq!(snapshot.total.denied, 2 * MAX_TRACKED as u64);
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _},
+    time::timeout,
+};
+
+use crate::Error;
+
+/// Whether CONNECTs to bare IPs are held until the client names the host it
+/// wants, see [`Server::set_host_peeking`](crate::Server::set_host_peeking).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostPeeking {
+    #[default]
+    Off,
+    /// Filter the host named by the first bytes again, connections which
+    /// name none go through as they are.
+    On,
+    /// Like `On`, but connections which name no host are refused.
+    Required,
+}
+
+/// Largest TLS record a ClientHello can come in, plus its header.
+const MAX_PEEK: usize = 5 + (1 << 14);
+
+/// What the first bytes of a connection say about its destination.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Sniffed {
+    Host(String),
+    NoHost,
+    NeedMore,
+}
+
+/// Reads the first bytes the client sends until they name a host or
+/// clearly won't, for up to `wait`. Returns every byte read, which still has
+/// to be sent on to the destination.
+pub(crate) async fn peek_host<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    wait: Duration,
+) -> Result<(Vec<u8>, Option<String>), Error> {
+    let mut buf = Vec::with_capacity(1024);
+    let read = async {
+        loop {
+            if buf.len() >= MAX_PEEK {
+                return Ok(Sniffed::NoHost);
+            }
+            let mut chunk = [0u8; 1024];
+            let len = stream.read(&mut chunk).await?;
+            if len == 0 {
+                return Ok(Sniffed::NoHost);
+            }
+            buf.extend_from_slice(&chunk[..len]);
+            match sniff(&buf) {
+                Sniffed::NeedMore => continue,
+                sniffed => return Ok::<_, Error>(sniffed),
+            }
+        }
+    };
+    // clients of protocols where the server speaks first send nothing
+    let host = match timeout(wait, read).await {
+        Ok(Ok(Sniffed::Host(host))) => Some(host),
+        Ok(Ok(_)) | Err(_) => None,
+        Ok(Err(e)) => return Err(e),
+    };
+    Ok((buf, host))
+}
+
+/// Finds the host in a TLS ClientHello's SNI or an HTTP/1 `Host` header.
+pub(crate) fn sniff(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::NeedMore,
+        Some(0x16) => sniff_tls(buf),
+        Some(b'A'..=b'Z') => sniff_http(buf),
+        Some(_) => Sniffed::NoHost,
+    }
+}
+
+/// Reads big endian length prefixed fields off a byte slice.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (field, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn number(&mut self, bytes: usize) -> Option<usize> {
+        let field = self.take(bytes)?;
+        Some(field.iter().fold(0, |n, b| n << 8 | *b as usize))
+    }
+
+    /// A field preceded by its length in `bytes` bytes.
+    fn prefixed(&mut self, bytes: usize) -> Option<&'a [u8]> {
+        let len = self.number(bytes)?;
+        self.take(len)
+    }
+}
+
+fn sniff_tls(buf: &[u8]) -> Sniffed {
+    if buf.get(1).is_some_and(|major| *major != 0x03) {
+        return Sniffed::NoHost;
+    }
+    let mut record = Fields(buf);
+    // content type and version
+    record.take(3);
+    let Some(len) = record.number(2) else {
+        return Sniffed::NeedMore;
+    };
+    let Some(hello) = record.take(len) else {
+        return Sniffed::NeedMore;
+    };
+    let mut hello = Fields(hello);
+    // a ClientHello spread over several records is left alone
+    let Some(&[0x01]) = hello.take(1) else {
+        return Sniffed::NoHost;
+    };
+    let found = (|| {
+        let mut body = Fields(hello.prefixed(3)?);
+        // version and random
+        body.take(2 + 32)?;
+        body.prefixed(1)?; // session id
+        body.prefixed(2)?; // cipher suites
+        body.prefixed(1)?; // compression methods
+        let mut extensions = Fields(body.prefixed(2)?);
+        while let Some(kind) = extensions.number(2) {
+            let data = extensions.prefixed(2)?;
+            if kind != 0x0000 {
+                continue;
+            }
+            let mut names = Fields(Fields(data).prefixed(2)?);
+            while let Some(name_type) = names.number(1) {
+                let name = names.prefixed(2)?;
+                if name_type == 0x00 {
+                    return std::str::from_utf8(name).ok().map(str::to_string);
+                }
+            }
+        }
+        None
+    })();
+    match found {
+        Some(host) => Sniffed::Host(host.to_ascii_lowercase()),
+        None => Sniffed::NoHost,
+    }
+}
+
+fn sniff_http(buf: &[u8]) -> Sniffed {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return match buf.len() < MAX_PEEK {
+            true => Sniffed::NeedMore,
+            false => Sniffed::NoHost,
+        };
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Sniffed::NoHost;
+    };
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then(|| value.trim())
+    });
+    let Some(host) = host else {
+        return Sniffed::NoHost;
+    };
+    // drop the port, minding the brackets around IPv6 addresses
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split_once(']').map_or(v6, |(ip, _)| ip),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    };
+    match host.is_empty() {
+        true => Sniffed::NoHost,
+        false => Sniffed::Host(host.to_ascii_lowercase()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Sniffed, sniff};
+
+    /// A TLS 1.2 style ClientHello naming `host`, with an extension in
+    /// front of the SNI one.
+    pub(crate) fn client_hello(host: &str) -> Vec<u8> {
+        fn prefixed(bytes: usize, data: &[u8]) -> Vec<u8> {
+            let len = data.len().to_be_bytes();
+            let mut out = len[len.len() - bytes..].to_vec();
+            out.extend_from_slice(data);
+            out
+        }
+        let mut names = vec![0x00];
+        names.extend(prefixed(2, host.as_bytes()));
+        let mut sni = vec![0x00, 0x00];
+        sni.extend(prefixed(2, &prefixed(2, &names)));
+        // supported groups
+        let mut extensions = vec![0x00, 0x0a];
+        extensions.extend(prefixed(2, &prefixed(2, &[0x00, 0x1d])));
+        extensions.extend(sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0x42; 32]);
+        body.extend(prefixed(1, &[7; 32]));
+        body.extend(prefixed(2, &[0x13, 0x01, 0xc0, 0x2f]));
+        body.extend(prefixed(1, &[0x00]));
+        body.extend(prefixed(2, &extensions));
+        let mut hello = vec![0x01];
+        hello.extend(prefixed(3, &body));
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend(prefixed(2, &hello));
+        record
+    }
+
+    #[test]
+    fn finds_sni_in_client_hellos() {
+        let hello = client_hello("Example.com");
+        assert_eq!(sniff(&hello), Sniffed::Host("example.com".into()));
+        for len in [0, 1, 4, 5, hello.len() - 1] {
+            assert_eq!(sniff(&hello[..len]), Sniffed::NeedMore, "{len}");
+        }
+        let mut garbled = hello.clone();
+        // claims to be longer than the record holding it
+        garbled[6] = 0xff;
+        assert_eq!(sniff(&garbled), Sniffed::NoHost);
+        assert_eq!(
+            sniff(&[0x16, 0x03, 0x01, 0x00, 0x01, 0x02]),
+            Sniffed::NoHost
+        );
+        assert_eq!(sniff(b"\x00\x01binary"), Sniffed::NoHost);
+    }
+
+    #[test]
+    fn finds_http_host_headers() {
+        let req =
+            b"GET / HTTP/1.1\r\nAccept: */*\r\nHOST: Example.com:8080\r\n\r\n";
+        assert_eq!(sniff(req), Sniffed::Host("example.com".into()));
+        assert_eq!(sniff(&req[..20]), Sniffed::NeedMore);
+        let v6 = b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n";
+        assert_eq!(sniff(v6), Sniffed::Host("::1".into()));
+        let none = b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n";
+        assert_eq!(sniff(none), Sniffed::NoHost);
+    }
+}
@@ -1,78 +1,328 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use log::{debug, error};
+use log::{debug, error, trace};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
+    io::AsyncWriteExt as _,
+    net::{TcpListener, TcpStream, UdpSocket},
     task::JoinHandle,
-    time::{sleep, timeout},
+    time::timeout,
 };
 
-use crate::{Addr, Error, response::Response};
+#[cfg(all(target_os = "linux", feature = "splice"))]
+use crate::splice;
+use crate::{
+    Addr, Error,
+    auth::Identity,
+    filter::{ConnectionContext, Decision, Limits, Policy},
+    metrics::Tracker,
+    ratelimit::Admission,
+    response::Front,
+    throttle::{Throttled, TokenBucket},
+    timeouts::{IdleTimer, after},
+    transport::ClientStream,
+    udp::UdpRelay,
+};
 
 pub struct Proxy {
     pub handle: JoinHandle<()>,
 }
 
-pub struct ProxyError(pub Error, pub TcpStream);
+pub struct ProxyError(pub Error, pub ClientStream);
 
-impl<IE: Into<Error>> From<(IE, TcpStream)> for ProxyError {
-    fn from(value: (IE, TcpStream)) -> Self {
+impl<IE: Into<Error>> From<(IE, ClientStream)> for ProxyError {
+    fn from(value: (IE, ClientStream)) -> Self {
         ProxyError(value.0.into(), value.1)
     }
 }
 
 impl Proxy {
+    /// Connects to the destination, through the decision's upstream if it
+    /// has one, within the connect timeout and relays between it and the
+    /// client on a background task.
+    ///
+    /// `peeked` holds what a client which was answered before dialing sent
+    /// already, it goes to the destination first.
     pub async fn run_tcp(
-        target_addr: Addr,
-        mut client_stream: TcpStream,
+        decision: Decision,
+        policy: &Policy,
+        front: &Front,
+        mut client_stream: ClientStream,
+        mut tracker: Tracker,
+        admission: Admission,
+        peeked: Option<Vec<u8>>,
     ) -> Result<Self, ProxyError> {
-        let addr: SocketAddr = match TryInto::<SocketAddr>::try_into(
-            match target_addr.resolve_dns().await {
+        let Decision {
+            ctx,
+            limits,
+            upstream,
+            ..
+        } = decision;
+        let connect = upstream.connect(&ctx, policy.resolver.as_ref());
+        let connected = match limits.connect_timeout {
+            Some(connect_timeout) => match timeout(connect_timeout, connect)
+                .await
+            {
                 Ok(v) => v,
-                Err(e) => return Err(ProxyError(e, client_stream)),
+                Err(_) => return Err((Error::TtlExpired, client_stream).into()),
             },
-        ) {
+            None => connect.await,
+        };
+        let mut outgoing_stream = match connected {
+            Ok(v) => v,
+            Err(e) => return Err((e, client_stream).into()),
+        };
+        let res = match &peeked {
+            None => {
+                let connected =
+                    front.connected(&mut client_stream, &mut outgoing_stream);
+                connected.await
+            }
+            Some(peeked) => {
+                outgoing_stream.write_all(peeked).await.map_err(Error::from)
+            }
+        };
+        if let Err(e) = res {
+            return Err((e, client_stream).into());
+        };
+        if let Some(peeked) = &peeked {
+            tracker.up(peeked.len() as u64);
+        }
+        tracker.connected(outgoing_stream.peer_addr().ok().map(|a| a.ip()));
+        let kill_switch = policy.kill_switch.clone();
+        let revoked = kill_switch.revoked(ctx.identity, Some(ctx.addr));
+        let handle = tokio::spawn(Self::transfer(
+            outgoing_stream,
+            client_stream,
+            limits,
+            tracker,
+            admission,
+            revoked,
+        ));
+        Ok(Self { handle })
+    }
+
+    /// Binds a UDP relay for the client and tells it where to send its
+    /// datagrams. The relay stops once `client_stream` closes.
+    ///
+    /// Datagrams the admission's buckets can't spare the bytes for are
+    /// dropped, holding them back would hold up every other one.
+    pub async fn run_udp(
+        client_hint: Addr,
+        client_stream: ClientStream,
+        identity: Identity,
+        policy: Arc<Policy>,
+        tracker: Tracker,
+        admission: Admission,
+    ) -> Result<Self, ProxyError> {
+        let mut relay = UdpRelay::bind(
+            client_hint,
+            client_stream,
+            identity,
+            policy,
+            tracker,
+            admission,
+        )
+        .await
+        .map_err(|(e, stream)| ProxyError(e, stream))?;
+        let relay_addr = match relay.local_addr() {
             Ok(addr) => addr,
-            Err(e) => return Err(ProxyError(e, client_stream)),
+            Err(e) => return Err(ProxyError(e, relay.into_control())),
         };
-        let outgoing_stream =
-            match match timeout(Duration::new(30, 0), TcpStream::connect(addr))
-                .await
-                .map_err(|_| {
-                    Error::Internal("timed out on connecting to proxy address")
-                }) {
-                Ok(v) => v,
-                Err(e) => return Err((e, client_stream).into()),
-            } {
-                Ok(v) => v,
-                Err(e) => return Err((e, client_stream).into()),
-            };
-        let local_addr = match outgoing_stream.local_addr() {
+        let bound = Addr::from_ip_addr(relay_addr.ip(), relay_addr.port());
+        let res = Front::Socks5.reply(relay.control(), bound);
+        if let Err(e) = res.await {
+            return Err(ProxyError(e, relay.into_control()));
+        }
+        relay.connected();
+        let handle = tokio::spawn(relay.run());
+        Ok(Self { handle })
+    }
+
+    /// Listens for the single inbound connection a BIND asks for. The first
+    /// reply carries the address being listened on, the second one is sent
+    /// from the background task once a peer connects and carries its address,
+    /// after which both sides are spliced.
+    ///
+    /// The peer is run through the filters before it is let through.
+    pub async fn run_bind(
+        decision: Decision,
+        front: Front,
+        mut client_stream: ClientStream,
+        policy: Arc<Policy>,
+        mut tracker: Tracker,
+        admission: Admission,
+    ) -> Result<Self, ProxyError> {
+        let Decision { ctx, limits, .. } = decision;
+        let requested = match ctx.target() {
+            Ok(v) => v,
+            Err(e) => return Err((e, client_stream).into()),
+        };
+        let listen_ip = match Self::bind_ip(requested, &client_stream).await {
+            Ok(ip) => ip,
+            Err(e) => return Err((e, client_stream).into()),
+        };
+        let listener = match TcpListener::bind((listen_ip, 0)).await {
             Ok(v) => v,
             Err(e) => return Err((e, client_stream).into()),
         };
-        let external_addr =
-            Addr::from_ip_addr(local_addr.ip(), local_addr.port());
-        let res = Response::from_addr(external_addr);
-        if let Err(e) = res.to_stream(&mut client_stream).await {
+        let listen_addr = match listener.local_addr() {
+            Ok(v) => v,
+            Err(e) => return Err((e, client_stream).into()),
+        };
+        let bound = Addr::from_ip_addr(listen_addr.ip(), listen_addr.port());
+        if let Err(e) = front.reply(&mut client_stream, bound).await {
             return Err((e, client_stream).into());
         };
-        let handle =
-            tokio::spawn(Self::transfer(outgoing_stream, client_stream));
-        let aborter = handle.abort_handle();
-        tokio::spawn(async move {
-            sleep(Duration::new(60, 0)).await;
-            aborter.abort();
+        tracker.connected(None);
+        trace!("BIND for {} listening on {listen_addr}", ctx.identity);
+        let handle = tokio::spawn(async move {
+            let wait = limits.bind_timeout;
+            let accepted =
+                Self::accept_bind(listener, wait, &ctx, &policy).await;
+            let (incoming_stream, peer_addr) = match accepted {
+                Ok((incoming_stream, peer_addr)) => {
+                    if let Addr::Ip(ip, _) = peer_addr {
+                        tracker.connected(Some(ip));
+                    }
+                    let reply =
+                        front.reply(&mut client_stream, peer_addr.clone());
+                    match reply.await {
+                        Ok(()) => (incoming_stream, peer_addr),
+                        Err(e) => return debug!("BIND reply failed: {e}"),
+                    }
+                }
+                Err(e) => {
+                    debug!("BIND for {} failed: {e}", ctx.identity);
+                    let _ = front.refuse(&mut client_stream, &e).await;
+                    return;
+                }
+            };
+            let kill_switch = policy.kill_switch.clone();
+            let revoked = kill_switch.revoked(ctx.identity, Some(peer_addr));
+            Self::transfer(
+                incoming_stream,
+                client_stream,
+                limits,
+                tracker,
+                admission,
+                revoked,
+            )
+            .await
         });
         Ok(Self { handle })
     }
-    async fn transfer<A, B>(mut a: A, mut b: B)
-    where
-        A: AsyncRead + AsyncWrite + Unpin,
-        B: AsyncRead + AsyncWrite + Unpin,
-    {
-        match tokio::io::copy_bidirectional(&mut a, &mut b).await {
+
+    /// Waits up to `wait` for the peer of a BIND and runs it through the
+    /// filters.
+    async fn accept_bind(
+        listener: TcpListener,
+        wait: Option<Duration>,
+        ctx: &ConnectionContext,
+        policy: &Policy,
+    ) -> Result<(TcpStream, Addr), Error> {
+        let accept = listener.accept();
+        let accepted = match wait {
+            Some(wait) => timeout(wait, accept).await,
+            None => Ok(accept.await),
+        };
+        let (incoming_stream, peer) = match accepted {
+            Ok(v) => v?,
+            Err(_) => return Err(Error::TtlExpired),
+        };
+        let peer_addr = Addr::from_ip_addr(peer.ip(), peer.port());
+        let mut peer_ctx = ctx.clone();
+        peer_ctx.addr = peer_addr.clone();
+        let decision = policy.evaluate(peer_ctx).await?;
+        if decision.ctx.target()? != peer {
+            return Err(Error::Internal("a BIND peer can't be redirected"));
+        }
+        Ok((incoming_stream, peer_addr))
+    }
+
+    /// Picks the local address a peer at `requested_addr` would reach us on.
+    /// Clients which don't know the peer yet send zeros, for them the
+    /// address the client reached us on is used.
+    async fn bind_ip(
+        requested: SocketAddr,
+        client_stream: &ClientStream,
+    ) -> Result<IpAddr, Error> {
+        if requested.ip().is_unspecified() {
+            return Ok(client_stream.local().ip());
+        }
+        // connecting a UDP socket sends nothing but makes the OS pick the
+        // interface which routes to the peer
+        let unspecified = match requested {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let probe = UdpSocket::bind((unspecified, 0)).await?;
+        probe.connect(requested).await?;
+        Ok(probe.local_addr()?.ip())
+    }
+
+    /// Relays between both sides until either closes, goes idle for longer
+    /// than the idle timeout, outlives the max lifetime or is `revoked` by
+    /// the kill switch. Reads are throttled by the connection's own limit and
+    /// the shared buckets it was admitted with.
+    ///
+    /// On Linux, a TCP client with nothing to throttle is relayed with
+    /// splice(2) instead, the bytes never pass through our buffers.
+    async fn transfer(
+        remote: TcpStream,
+        client: ClientStream,
+        limits: Limits,
+        tracker: Tracker,
+        admission: Admission,
+        revoked: impl Future<Output = ()>,
+    ) {
+        let idle = IdleTimer::new();
+        // the slot stays taken until the transfer is done
+        let Admission {
+            mut up,
+            mut down,
+            slot: _slot,
+        } = admission;
+        if let Some(rate) = limits.bytes_per_sec {
+            up.push(TokenBucket::shared(rate));
+            down.push(TokenBucket::shared(rate));
+        }
+        let copy = async {
+            #[cfg(all(target_os = "linux", feature = "splice"))]
+            if up.is_empty()
+                && down.is_empty()
+                && let Some(tcp) = client.as_tcp()
+            {
+                let (count_up, count_down) =
+                    (|len| tracker.up(len), |len| tracker.down(len));
+                let touch = || idle.touch();
+                trace!("relaying with splice");
+                return splice::relay(
+                    &remote, tcp, count_up, count_down, touch,
+                )
+                .await;
+            }
+            let client = tracker.count(client);
+            let mut remote = Throttled::new(idle.track(remote), down);
+            let mut client = Throttled::new(idle.track(client), up);
+            tokio::io::copy_bidirectional(&mut remote, &mut client).await
+        };
+        let res = tokio::select! {
+            res = copy => res,
+            _ = idle.expired(limits.idle_timeout) => {
+                return debug!("transfer was idle for too long");
+            }
+            _ = after(limits.max_lifetime) => {
+                return debug!("transfer reached its max lifetime");
+            }
+            _ = revoked => {
+                return debug!("transfer was closed by the kill switch");
+            }
+        };
+        match res {
             Ok(res) => debug!("transfer closed ({}, {})", res.0, res.1),
             Err(err) => error!("transfer error: {:?}", err),
         };
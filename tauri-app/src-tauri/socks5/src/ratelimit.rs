@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use log::debug;
+use tokio::time::Instant;
+
+use crate::{
+    Addr, Error,
+    auth::Identity,
+    caps::Slot,
+    throttle::{SharedBucket, TokenBucket},
+};
+
+/// Token bucket rates for a group of connections, unset ones don't limit.
+///
+/// Each bucket holds one second's worth, so a burst of up to the rate goes
+/// through at once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rate {
+    /// Bytes per second clients send to their destinations.
+    pub bytes_up: Option<u64>,
+    /// Bytes per second destinations send back to clients.
+    pub bytes_down: Option<u64>,
+    /// New connections per second, requests beyond it are refused.
+    pub connections: Option<u64>,
+}
+
+/// Rates shared by groups of connections, on top of the per connection
+/// [`Limits::bytes_per_sec`](crate::Limits::bytes_per_sec).
+///
+/// Relayed TCP streams are slowed down to the rates, UDP associations drop
+/// the datagrams which would exceed them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Shared by every connection of the server.
+    pub global: Rate,
+    /// Applies to each identity separately.
+    pub per_identity: Rate,
+    /// Applies to each destination separately, keyed by the host the client
+    /// asked for.
+    pub per_host: Rate,
+    /// Shared by the connections filters put in the class with
+    /// [`FilterResult::LimitClass`](crate::FilterResult::LimitClass).
+    pub classes: HashMap<String, Rate>,
+}
+
+/// Upper bound on the identities and hosts tracked, buckets which refilled
+/// completely are dropped once it is reached. If none did, the newcomers
+/// share one group of buckets.
+const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug, Default)]
+struct Buckets {
+    up: Option<SharedBucket>,
+    down: Option<SharedBucket>,
+    connections: Option<SharedBucket>,
+}
+
+impl Buckets {
+    fn new(rate: &Rate) -> Self {
+        Self {
+            up: rate.bytes_up.map(TokenBucket::shared),
+            down: rate.bytes_down.map(TokenBucket::shared),
+            connections: rate.connections.map(TokenBucket::shared),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &SharedBucket> {
+        [&self.up, &self.down, &self.connections]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Whether nothing relies on the buckets anymore, so they can be made
+    /// again from scratch without anyone noticing.
+    fn is_unused(&self, now: Instant) -> bool {
+        self.iter().all(|bucket| {
+            Arc::strong_count(bucket) == 1
+                && bucket
+                    .lock()
+                    .expect("bucket to not be poisoned")
+                    .is_full(now)
+        })
+    }
+}
+
+/// What a connection was let in with, kept until it closes: the shared
+/// buckets its bytes are drawn from and its slot under the connection caps.
+#[derive(Debug, Default)]
+pub(crate) struct Admission {
+    pub up: Vec<SharedBucket>,
+    pub down: Vec<SharedBucket>,
+    pub slot: Option<Slot>,
+}
+
+/// Keeps the buckets of a server's [`RateLimits`], shared by its clones.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    global: Buckets,
+    classes: HashMap<String, Buckets>,
+    identities: Mutex<HashMap<Identity, Buckets>>,
+    hosts: Mutex<HashMap<String, Buckets>>,
+    /// Shared by the identities and hosts there was no room for.
+    overflow_identities: Buckets,
+    overflow_hosts: Buckets,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            global: Buckets::new(&limits.global),
+            classes: limits
+                .classes
+                .iter()
+                .map(|(class, rate)| (class.clone(), Buckets::new(rate)))
+                .collect(),
+            identities: Mutex::default(),
+            hosts: Mutex::default(),
+            overflow_identities: Buckets::new(&limits.per_identity),
+            overflow_hosts: Buckets::new(&limits.per_host),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Takes a connection token from every bucket the request falls under
+    /// and hands out the ones its bytes are limited by. Requests are refused
+    /// with [`Error::RateLimited`] when any of them is empty, nothing is taken
+    /// then.
+    pub fn admit(
+        &self,
+        identity: &Identity,
+        host: Option<&Addr>,
+        class: Option<&str>,
+    ) -> Result<Admission, Error> {
+        let mut groups = vec![&self.global];
+        match class.map(|class| (class, self.classes.get(class))) {
+            Some((_, Some(buckets))) => groups.push(buckets),
+            Some((class, None)) => debug!("unknown limit class {class:?}"),
+            None => (),
+        }
+        let mut identities =
+            self.identities.lock().expect("limiter to not be poisoned");
+        let mut hosts = self.hosts.lock().expect("limiter to not be poisoned");
+        groups.push(Self::group(
+            &mut identities,
+            identity,
+            &self.limits.per_identity,
+            &self.overflow_identities,
+        ));
+        if let Some(host) = host.and_then(Addr::host) {
+            groups.push(Self::group(
+                &mut hosts,
+                &host,
+                &self.limits.per_host,
+                &self.overflow_hosts,
+            ));
+        }
+
+        // hold every lock so concurrent requests can't both take the last
+        // token of different buckets
+        let now = Instant::now();
+        let mut connections = groups
+            .iter()
+            .filter_map(|group| group.connections.as_ref())
+            .map(|bucket| bucket.lock().expect("bucket to not be poisoned"))
+            .collect::<Vec<_>>();
+        if connections
+            .iter_mut()
+            .any(|bucket| bucket.available(now) == 0)
+        {
+            return Err(Error::RateLimited);
+        }
+        for bucket in &mut connections {
+            bucket.consume(1);
+        }
+        Ok(Admission {
+            up: groups.iter().filter_map(|g| g.up.clone()).collect(),
+            down: groups.iter().filter_map(|g| g.down.clone()).collect(),
+            slot: None,
+        })
+    }
+
+    /// The buckets of `key`, made from `rate` if it has none yet, or
+    /// `overflow` if there's no room for them.
+    fn group<'a, K, Q>(
+        groups: &'a mut HashMap<K, Buckets>,
+        key: &Q,
+        rate: &Rate,
+        overflow: &'a Buckets,
+    ) -> &'a Buckets
+    where
+        K: Eq + Hash + std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ToOwned<Owned = K> + ?Sized,
+    {
+        if !groups.contains_key(key) && groups.len() >= MAX_TRACKED {
+            let now = Instant::now();
+            groups.retain(|_, buckets| !buckets.is_unused(now));
+            if groups.len() >= MAX_TRACKED {
+                return overflow;
+            }
+        }
+        groups
+            .entry(key.to_owned())
+            .or_insert_with(|| Buckets::new(rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MAX_TRACKED, Rate, RateLimiter, RateLimits};
+    use crate::{Addr, Identity, error::Error};
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_are_limited_per_group() {
+        let limiter = RateLimiter::new(RateLimits {
+            global: Rate {
+                bytes_up: Some(1000),
+                ..Rate::default()
+            },
+            per_identity: Rate {
+                connections: Some(2),
+                ..Rate::default()
+            },
+            per_host: Rate {
+                connections: Some(1),
+                bytes_down: Some(500),
+                ..Rate::default()
+            },
+            classes: [(
+                "bulk".to_string(),
+                Rate {
+                    bytes_up: Some(10),
+                    ..Rate::default()
+                },
+            )]
+            .into(),
+        });
+        let user = Identity::User("doc-1".into());
+        let a = Addr::Domain("a.test".into(), 443);
+        let b = Addr::Domain("b.test".into(), 443);
+
+        let admission = limiter.admit(&user, Some(&a), Some("bulk")).unwrap();
+        assert_eq!((admission.up.len(), admission.down.len()), (2, 1));
+        assert!(matches!(
+            limiter.admit(&user, Some(&a), None),
+            Err(Error::RateLimited)
+        ));
+        // a refused request takes no tokens, so its identity has one left
+        limiter.admit(&user, Some(&b), None).unwrap();
+        assert!(matches!(
+            limiter.admit(&user, None, None),
+            Err(Error::RateLimited)
+        ));
+        limiter.admit(&Identity::Anonymous, None, None).unwrap();
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.admit(&user, Some(&a), None).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hosts_past_the_bound_share_buckets() {
+        let limiter = RateLimiter::new(RateLimits {
+            per_host: Rate {
+                connections: Some(1),
+                ..Rate::default()
+            },
+            ..RateLimits::default()
+        });
+        let host = |name: &str| Addr::Domain(name.into(), 443);
+        // none of them refills while the clock stands still
+        for n in 0..MAX_TRACKED {
+            let busy = host(&format!("{n}.busy.test"));
+            limiter
+                .admit(&Identity::Anonymous, Some(&busy), None)
+                .unwrap();
+        }
+        let user = Identity::Anonymous;
+        limiter.admit(&user, Some(&host("a.test")), None).unwrap();
+        assert!(matches!(
+            limiter.admit(&user, Some(&host("b.test")), None),
+            Err(Error::RateLimited)
+        ));
+        assert_eq!(limiter.hosts.lock().unwrap().len(), MAX_TRACKED);
+    }
+}
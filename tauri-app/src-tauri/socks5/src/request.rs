@@ -1,73 +1,341 @@
+use std::net::{IpAddr, SocketAddr};
+
 use log::trace;
-use tokio::io::AsyncReadExt as _;
-use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
 
+use crate::audit::Audit;
+use crate::auth::Identity;
+use crate::codec;
+use crate::filter::{ConnectionContext, Decision, Refused};
+use crate::http::HttpRequest;
+use crate::peek::{self, HostPeeking, Sniffed};
 use crate::proxy::Proxy;
-use crate::response::Response;
-use crate::server::FilterResult;
+use crate::ratelimit::Admission;
+use crate::response::Front;
+use crate::server::Config;
+use crate::socks4::Socks4Request;
+use crate::transport::ClientStream;
 
 use super::Addr;
 use super::Cmd;
 use super::Error;
 
-pub type Filter<'a> = dyn Fn(&Addr) -> FilterResult + 'a;
-
 pub struct Request<'a> {
     cmd: Cmd,
     addr: Addr,
-    filters: &'a Vec<Box<Filter<'a>>>,
+    peer: SocketAddr,
+    identity: Identity,
+    config: &'a Config,
+    front: Front,
 }
 
 impl<'a> Request<'a> {
     pub async fn from_stream(
-        stream: &mut TcpStream,
-        filters: &'a Vec<Box<Filter<'a>>>,
+        stream: &mut ClientStream,
+        identity: Identity,
+        config: &'a Config,
+    ) -> Result<Self, Error> {
+        let peer = stream.peer();
+        let codec::Request { cmd, addr } = match codec::read(stream).await {
+            Ok(req) => req,
+            Err(e) => {
+                // a client which sent something we can't serve is told why
+                if !matches!(e, Error::Io(_)) {
+                    let _ = Front::Socks5.refuse(stream, &e).await;
+                }
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            cmd,
+            addr,
+            peer,
+            identity,
+            config,
+            front: Front::Socks5,
+        })
+    }
+
+    /// Turns an HTTP proxy request into a CONNECT.
+    pub fn from_http(
+        stream: &ClientStream,
+        identity: Identity,
+        req: HttpRequest,
+        config: &'a Config,
+    ) -> Result<Self, Error> {
+        let front = match req.forward {
+            Some(head) => Front::HttpForward(head),
+            None => Front::HttpConnect,
+        };
+        Ok(Self {
+            cmd: Cmd::Connect,
+            addr: req.addr,
+            peer: stream.peer(),
+            identity,
+            config,
+            front,
+        })
+    }
+
+    /// Takes a SOCKS4 or SOCKS4a request as is, its user id is not an
+    /// identity anyone vouched for.
+    pub fn from_socks4(
+        stream: &ClientStream,
+        req: Socks4Request,
+        config: &'a Config,
     ) -> Result<Self, Error> {
-        let ver = stream.read_u8().await?;
-        if ver != 0x05 {
-            return Err(Error::VersionMismatch);
+        Ok(Self {
+            cmd: req.cmd,
+            addr: req.addr,
+            peer: stream.peer(),
+            identity: Identity::Anonymous,
+            config,
+            front: Front::Socks4,
+        })
+    }
+
+    /// Runs the filters and fills in the limits they left unset.
+    async fn evaluate(
+        &self,
+        ctx: ConnectionContext,
+    ) -> Result<Decision, Refused> {
+        let mut decision = self.config.policy.evaluate(ctx).await?;
+        decision.limits = self.config.timeouts.apply(decision.limits);
+        Ok(decision)
+    }
+
+    /// With host peeking on, filters a CONNECT to a bare IP again for the
+    /// host its first bytes name, and connects there instead. Ones which name
+    /// none are filtered again as they are, the first pass was only a
+    /// preview. Other clients are told they are connected so they start
+    /// sending; what they sent is returned to go on to the destination.
+    ///
+    /// Fails with whether the client was told it is connected already.
+    async fn peek(
+        &self,
+        stream: &mut ClientStream,
+        decision: Decision,
+    ) -> Result<(Decision, Option<Vec<u8>>), (Refused, bool)> {
+        if !decision.ctx.will_peek {
+            return Ok((decision, None));
+        }
+        let mode = self.config.host_peeking;
+        let (peeked, host) = match &self.front {
+            Front::HttpForward(head) => match peek::sniff(head) {
+                Sniffed::Host(host) => (None, Some(host)),
+                _ => (None, None),
+            },
+            front => {
+                if let Err(e) = front.accepted(stream).await {
+                    return Err((e.into(), true));
+                }
+                let wait = self.config.timeouts.handshake;
+                match peek::peek_host(stream, wait).await {
+                    Ok((peeked, host)) => (Some(peeked), host),
+                    Err(e) => return Err((e.into(), true)),
+                }
+            }
+        };
+        let answered = peeked.is_some();
+        let mut ctx = decision.ctx;
+        ctx.will_peek = false;
+        let host = match host {
+            Some(host) => host,
+            None if mode == HostPeeking::Required => {
+                let err = Error::BreaksRuleset;
+                return Err((Refused::by(err, "host peeking"), answered));
+            }
+            None => {
+                return match self.evaluate(self.context()).await {
+                    Ok(decision) => Ok((decision, peeked)),
+                    Err(refused) => Err((refused, answered)),
+                };
+            }
+        };
+        trace!("{:?} was peeked to be for {host}", self.addr);
+        let port = self.addr.port();
+        ctx.addr = match host.parse::<IpAddr>() {
+            Ok(ip) => Addr::Ip(ip, port),
+            Err(_) => match Addr::try_from_domain(host, port).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    return Err((Refused::by(e, "host peeking"), answered));
+                }
+            },
+        };
+        match self.evaluate(ctx).await {
+            Ok(decision) => Ok((decision, peeked)),
+            Err(refused) => Err((refused, answered)),
+        }
+    }
+
+    /// Waits for a slot under the connection caps, then takes a token from
+    /// every connection bucket the request falls under.
+    async fn admit(&self, class: Option<&str>) -> Result<Admission, Refused> {
+        let caps = &self.config.caps;
+        let slot = caps
+            .acquire(&self.identity, self.host())
+            .await
+            .map_err(|e| Refused::by(e, "connection limits"))?;
+        let limiter = &self.config.rate_limiter;
+        let mut admission = limiter
+            .admit(&self.identity, self.host(), class)
+            .map_err(|e| Refused::by(e, "rate limits"))?;
+        admission.slot = Some(slot);
+        Ok(admission)
+    }
+
+    /// The destination metrics are kept under, a UDP associate only names
+    /// the client.
+    fn host(&self) -> Option<&Addr> {
+        match self.cmd {
+            Cmd::UdpAssociate => None,
+            _ => Some(&self.addr),
         }
-        let cmd: Cmd = stream.read_u8().await?.try_into()?;
-        let _rsv = stream.read_u8().await?;
-        let addr = Addr::from_stream(stream).await?;
-        Ok(Self { cmd, addr, filters })
     }
 
+    fn context(&self) -> ConnectionContext {
+        ConnectionContext::new(
+            self.peer,
+            self.cmd,
+            self.identity.clone(),
+            self.addr.clone(),
+        )
+    }
+
+    /// Fails with the client's stream, unless it was told it is connected
+    /// already and there's nothing left to tell it.
     async fn handle_inner(
         &self,
-        stream: TcpStream,
-    ) -> Result<JoinHandle<()>, (Error, TcpStream)> {
-        match self.cmd {
-            Cmd::Connect => (),
-            Cmd::UdpAssociate => {}
-            cmd => {
-                return Err((Error::CmdNotSupported(cmd), stream));
+        mut stream: ClientStream,
+        audit: Audit,
+    ) -> Result<JoinHandle<()>, (Refused, Option<ClientStream>)> {
+        let mut answered = false;
+        let mut tracker =
+            self.config.metrics.track(&self.identity, self.host());
+        let success = self.front.reply_code(None);
+        let proxy = match self.cmd {
+            Cmd::Connect => {
+                trace!(
+                    "Handling request from {} to connect to {:?}",
+                    self.identity, self.addr
+                );
+                let mut ctx = self.context();
+                ctx.will_peek = self.config.host_peeking != HostPeeking::Off
+                    && matches!(self.addr, Addr::Ip(..));
+                let decision = match self.evaluate(ctx).await {
+                    Ok(v) => v,
+                    Err(e) => return Err((e, Some(stream))),
+                };
+                let (decision, peeked) =
+                    match self.peek(&mut stream, decision).await {
+                        Ok(v) => v,
+                        Err((e, true)) => return Err((e, None)),
+                        Err((e, false)) => return Err((e, Some(stream))),
+                    };
+                answered = peeked.is_some();
+                let admission =
+                    match self.admit(decision.class.as_deref()).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Err((e, (!answered).then_some(stream)));
+                        }
+                    };
+                let decided_by = decision.decided_by.clone();
+                tracker.audit(audit.allowed(decided_by, success));
+                Proxy::run_tcp(
+                    decision,
+                    &self.config.policy,
+                    &self.front,
+                    stream,
+                    tracker,
+                    admission,
+                    peeked,
+                )
+                .await
             }
-        }
-        trace!("Handling request to connect to {0:?}", self.addr);
-        for filter in self.filters.iter() {
-            if filter(&self.addr) == FilterResult::Deny {
-                return Err((Error::BreaksRuleset, stream));
+            Cmd::UdpAssociate => {
+                trace!(
+                    "Handling UDP associate from {} expecting {:?}",
+                    self.identity, self.addr
+                );
+                // the address is where the client will send from, every
+                // datagram's destination gets filtered by the relay instead
+                let admission = match self.admit(None).await {
+                    Ok(v) => v,
+                    Err(e) => return Err((e, Some(stream))),
+                };
+                tracker.audit(audit.allowed(None, success));
+                Proxy::run_udp(
+                    self.addr.clone(),
+                    stream,
+                    self.identity.clone(),
+                    self.config.policy.clone(),
+                    tracker,
+                    admission,
+                )
+                .await
             }
+            Cmd::Bind => {
+                trace!(
+                    "Handling request from {} to bind for {:?}",
+                    self.identity, self.addr
+                );
+                let decision = match self.evaluate(self.context()).await {
+                    Ok(v) => v,
+                    Err(e) => return Err((e, Some(stream))),
+                };
+                let admission =
+                    match self.admit(decision.class.as_deref()).await {
+                        Ok(v) => v,
+                        Err(e) => return Err((e, Some(stream))),
+                    };
+                let decided_by = decision.decided_by.clone();
+                tracker.audit(audit.allowed(decided_by, success));
+                Proxy::run_bind(
+                    decision,
+                    self.front.clone(),
+                    stream,
+                    self.config.policy.clone(),
+                    tracker,
+                    admission,
+                )
+                .await
+            }
+        };
+        match proxy {
+            Ok(Proxy { handle }) => Ok(handle),
+            Err(e) => Err((e.0.into(), (!answered).then_some(e.1))),
         }
-
-        let Proxy { handle } =
-            match Proxy::run_tcp(self.addr.clone(), stream).await {
-                Ok(proxy) => proxy,
-                Err(e) => return Err((e.0, e.1)),
-            };
-        Ok(handle)
     }
+
+    /// Starts the proxy, or refuses the client and audits why.
     pub async fn handle(
         &self,
-        stream: TcpStream,
+        stream: ClientStream,
     ) -> Result<JoinHandle<()>, Error> {
-        match self.handle_inner(stream).await {
-            Ok(v) => Ok(v),
-            Err((e, mut stream)) => {
-                Response::from_error(&e).to_stream(&mut stream).await?;
-                Err(e)
+        let metrics = &self.config.metrics;
+        let audit = Audit::new(self.config.audit_sinks.clone(), self.peer)
+            .request(self.cmd, &self.identity, &self.addr);
+        match self.handle_inner(stream, audit.clone()).await {
+            Ok(v) => {
+                metrics.accept(&self.identity, self.host());
+                Ok(v)
+            }
+            Err((refused, stream)) => {
+                let e = &refused.err;
+                metrics.deny(e, Some(&self.identity), self.host());
+                let (refusal, reply) = match stream {
+                    Some(mut stream) => (
+                        self.front.refuse(&mut stream, e).await,
+                        self.front.reply_code(Some(e)),
+                    ),
+                    // it only sees the connection close
+                    None => (Ok(()), self.front.reply_code(None)),
+                };
+                audit.denied(&refused, Some(reply));
+                refusal?;
+                Err(refused.err)
             }
         }
     }
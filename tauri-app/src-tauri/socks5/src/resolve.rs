@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::trace;
+use tokio::{net::lookup_host, time::Instant};
+
+use crate::{Error, filter::BoxFuture};
+
+/// Every address a name resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    pub ips: Vec<IpAddr>,
+    /// How long the answer may be cached, if the resolver knows.
+    pub ttl: Option<Duration>,
+}
+
+/// Turns domains into IP addresses for the proxy.
+///
+/// A name which doesn't resolve to anything is reported as
+/// [`Error::HostUnreachable`].
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>> {
+        (**self).resolve(domain)
+    }
+}
+
+/// Asks the operating system, the system resolver doesn't report TTLs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>> {
+        Box::pin(async move {
+            // the port is required but doesn't matter
+            let ips = match lookup_host((domain, 0)).await {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect::<Vec<_>>(),
+                Err(e) => {
+                    trace!("could not resolve {domain}: {e}");
+                    Vec::new()
+                }
+            };
+            if ips.is_empty() {
+                return Err(Error::HostUnreachable);
+            }
+            Ok(Lookup { ips, ttl: None })
+        })
+    }
+}
+
+/// Answers for a fixed set of names and hands everything else to another
+/// resolver, like a hosts file.
+pub struct StaticHosts<R> {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: R,
+}
+
+impl<R: Resolver> StaticHosts<R> {
+    pub fn new(fallback: R) -> Self {
+        Self {
+            hosts: HashMap::new(),
+            fallback,
+        }
+    }
+
+    /// Makes `domain` resolve to `ips`. Names are matched case
+    /// insensitively and without a trailing dot.
+    pub fn insert(
+        &mut self,
+        domain: &str,
+        ips: impl IntoIterator<Item = IpAddr>,
+    ) {
+        self.hosts
+            .insert(normalize(domain), ips.into_iter().collect());
+    }
+}
+
+impl<R: Resolver> Resolver for StaticHosts<R> {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>> {
+        match self.hosts.get(&normalize(domain)) {
+            Some(ips) if ips.is_empty() => {
+                Box::pin(std::future::ready(Err(Error::HostUnreachable)))
+            }
+            Some(ips) => Box::pin(std::future::ready(Ok(Lookup {
+                ips: ips.clone(),
+                ttl: None,
+            }))),
+            None => self.fallback.resolve(domain),
+        }
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Upper bound on cached names, expired ones are dropped once it is reached.
+const MAX_CACHED: usize = 4096;
+
+struct CacheEntry {
+    /// `None` for names which didn't resolve.
+    ips: Option<Vec<IpAddr>>,
+    expires: Instant,
+}
+
+/// Remembers the answers of another resolver for as long as their TTL says,
+/// or `ttl` when they don't have one. Names which don't resolve are
+/// remembered for `negative_ttl`.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    negative_ttl: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Caches for a minute, misses for five seconds.
+    pub fn new(inner: R) -> Self {
+        Self::with_ttl(inner, Duration::from_secs(60), Duration::from_secs(5))
+    }
+
+    pub fn with_ttl(inner: R, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            negative_ttl,
+            cache: Mutex::default(),
+        }
+    }
+
+    fn cached(&self, domain: &str) -> Option<Result<Lookup, Error>> {
+        let cache = self.cache.lock().expect("cache lock to not be poisoned");
+        let entry = cache.get(domain)?;
+        let now = Instant::now();
+        if entry.expires <= now {
+            return None;
+        }
+        Some(match &entry.ips {
+            Some(ips) => Ok(Lookup {
+                ips: ips.clone(),
+                ttl: Some(entry.expires - now),
+            }),
+            None => Err(Error::HostUnreachable),
+        })
+    }
+
+    fn store(&self, domain: String, res: &Result<Lookup, Error>) {
+        let (ips, ttl) = match res {
+            Ok(lookup) => {
+                (Some(lookup.ips.clone()), lookup.ttl.unwrap_or(self.ttl))
+            }
+            Err(Error::HostUnreachable) => (None, self.negative_ttl),
+            // anything else may well work on the next try
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        let mut cache =
+            self.cache.lock().expect("cache lock to not be poisoned");
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() < MAX_CACHED {
+            cache.insert(
+                domain,
+                CacheEntry {
+                    ips,
+                    expires: now + ttl,
+                },
+            );
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> BoxFuture<'a, Result<Lookup, Error>> {
+        Box::pin(async move {
+            let key = normalize(domain);
+            if let Some(res) = self.cached(&key) {
+                return res;
+            }
+            let res = self.inner.resolve(domain).await;
+            self.store(key, &res);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::{CachingResolver, Lookup, Resolver, StaticHosts};
+    use crate::{Error, filter::BoxFuture};
+
+    /// Resolves `known.test` with a 30 second TTL and counts its lookups.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl Resolver for Counting {
+        fn resolve<'a>(
+            &'a self,
+            domain: &'a str,
+        ) -> BoxFuture<'a, Result<Lookup, Error>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let res = match domain {
+                "known.test" => Ok(Lookup {
+                    ips: vec![IpAddr::from([192, 0, 2, 1])],
+                    ttl: Some(Duration::from_secs(30)),
+                }),
+                _ => Err(Error::HostUnreachable),
+            };
+            Box::pin(std::future::ready(res))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_honours_ttls() {
+        let cache = CachingResolver::with_ttl(
+            Counting::default(),
+            Duration::from_secs(300),
+            Duration::from_secs(5),
+        );
+        let lookups = || cache.inner.0.load(Ordering::Relaxed);
+
+        cache.resolve("known.test").await.unwrap();
+        let cached = cache.resolve("KNOWN.test.").await.unwrap();
+        assert_eq!(cached.ttl, Some(Duration::from_secs(30)));
+        assert_eq!(lookups(), 1);
+        tokio::time::advance(Duration::from_secs(31)).await;
+        cache.resolve("known.test").await.unwrap();
+        assert_eq!(lookups(), 2);
+
+        // misses are remembered for the negative TTL
+        assert!(cache.resolve("missing.test").await.is_err());
+        assert!(cache.resolve("missing.test").await.is_err());
+        assert_eq!(lookups(), 3);
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(cache.resolve("missing.test").await.is_err());
+        assert_eq!(lookups(), 4);
+    }
+
+    #[tokio::test]
+    async fn static_hosts_override_the_fallback() {
+        let mut hosts = StaticHosts::new(Counting::default());
+        let sinkhole = IpAddr::from([0, 0, 0, 0]);
+        hosts.insert("Known.Test", [sinkhole]);
+        hosts.insert("gone.test", []);
+
+        let lookup = hosts.resolve("known.test").await.unwrap();
+        assert_eq!(lookup.ips, [sinkhole]);
+        assert!(matches!(
+            hosts.resolve("gone.test").await,
+            Err(Error::HostUnreachable)
+        ));
+        assert_eq!(hosts.fallback.0.load(Ordering::Relaxed), 0);
+        assert!(hosts.resolve("other.test").await.is_err());
+        assert_eq!(hosts.fallback.0.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,64 +1,113 @@
-use tokio::{io::AsyncWriteExt as _, net::TcpStream};
+use std::net::Ipv4Addr;
 
-use crate::addr::Addr;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt as _},
+    net::TcpStream,
+};
 
-use super::Error;
-
-struct Reply(u8);
+use crate::{
+    addr::Addr,
+    codec::{self, Reply},
+    http, socks4,
+};
 
-impl Reply {
-    pub fn from_error(err: &Error) -> Self {
-        Reply(err.to_u8())
-    }
-}
+use super::Error;
 
-impl From<Error> for Reply {
-    fn from(value: Error) -> Self {
-        Self::from_error(&value)
-    }
+/// The protocol a client spoke, which decides how it gets answered.
+#[derive(Clone)]
+pub(crate) enum Front {
+    Socks5,
+    Socks4,
+    HttpConnect,
+    /// A plain HTTP request, its head still has to be sent on.
+    HttpForward(Vec<u8>),
 }
 
-impl Reply {
-    pub fn success() -> Self {
-        Self(0x00)
+impl Front {
+    /// Tells the client `remote` is connected, or passes its request on.
+    pub async fn connected<C: AsyncWrite + Unpin>(
+        &self,
+        client: &mut C,
+        remote: &mut TcpStream,
+    ) -> Result<(), Error> {
+        match self {
+            Front::Socks5 | Front::Socks4 => {
+                let local = remote.local_addr()?;
+                self.reply(client, Addr::from_ip_addr(local.ip(), local.port()))
+                    .await
+            }
+            Front::HttpConnect => {
+                Ok(client.write_all(http::ESTABLISHED).await?)
+            }
+            Front::HttpForward(head) => Ok(remote.write_all(head).await?),
+        }
     }
-}
 
-pub struct Response {
-    reply: Reply,
-    addr: Addr,
-}
-
-impl Response {
-    pub fn from_error(error: &Error) -> Self {
-        Self {
-            reply: Reply::from_error(error),
-            addr: Addr::Null,
+    /// Tells the client it is connected before anything was dialed, so it
+    /// starts sending. SOCKS clients get an unspecified bound address.
+    pub async fn accepted<C: AsyncWrite + Unpin>(
+        &self,
+        client: &mut C,
+    ) -> Result<(), Error> {
+        match self {
+            Front::Socks5 | Front::Socks4 => {
+                let unspecified = Ipv4Addr::UNSPECIFIED.into();
+                self.reply(client, Addr::from_ip_addr(unspecified, 0)).await
+            }
+            Front::HttpConnect => {
+                Ok(client.write_all(http::ESTABLISHED).await?)
+            }
+            Front::HttpForward(_) => Ok(()),
         }
     }
-    pub fn from_addr(addr: Addr) -> Self {
-        Self {
-            reply: Reply::success(),
-            addr,
+
+    /// The reply code or HTTP status a client gets for `err`, or for
+    /// success.
+    pub fn reply_code(&self, err: Option<&Error>) -> u16 {
+        match (self, err) {
+            (Front::Socks5, None) => 0x00,
+            (Front::Socks5, Some(err)) => err.to_u8().into(),
+            (Front::Socks4, err) => socks4::status(err).into(),
+            (Front::HttpConnect | Front::HttpForward(_), None) => 200,
+            (Front::HttpConnect | Front::HttpForward(_), Some(err)) => {
+                http::status(err).0
+            }
         }
     }
-    pub async fn to_stream(&self, stream: &mut TcpStream) -> Result<(), Error> {
-        stream.write_u8(0x05).await?; // version
-        stream.write_u8(self.reply.0).await?; // reply
-        stream.write_u8(0x00).await?; // reserved
-        self.addr.to_stream(stream).await?; // addr
-        Ok(())
-    }
-}
 
-impl From<Error> for Response {
-    fn from(value: Error) -> Self {
-        Self::from_error(&value)
+    /// Sends a SOCKS success reply carrying `addr`. HTTP has no such reply
+    /// past the first, so it gets nothing.
+    pub async fn reply<C: AsyncWrite + Unpin>(
+        &self,
+        client: &mut C,
+        addr: Addr,
+    ) -> Result<(), Error> {
+        match self {
+            Front::Socks5 => {
+                codec::write(client, &Reply { code: 0x00, addr }).await
+            }
+            Front::Socks4 => socks4::reply(client, Ok(&addr)).await,
+            Front::HttpConnect | Front::HttpForward(_) => Ok(()),
+        }
     }
-}
 
-impl From<Addr> for Response {
-    fn from(value: Addr) -> Self {
-        Self::from_addr(value)
+    pub async fn refuse<C: AsyncWrite + Unpin>(
+        &self,
+        client: &mut C,
+        err: &Error,
+    ) -> Result<(), Error> {
+        match self {
+            Front::Socks5 => {
+                // the address means nothing here, but it has to be one
+                let code = err.to_u8();
+                let unspecified = Ipv4Addr::UNSPECIFIED.into();
+                let addr = Addr::from_ip_addr(unspecified, 0);
+                codec::write(client, &Reply { code, addr }).await
+            }
+            Front::Socks4 => socks4::reply(client, Err(err)).await,
+            Front::HttpConnect | Front::HttpForward(_) => {
+                http::refuse(client, err).await
+            }
+        }
     }
 }
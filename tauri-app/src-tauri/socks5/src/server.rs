@@ -1,101 +1,505 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use log::{info, trace};
+use log::{debug, error, info};
 use tokio::{
-    io::{self, AsyncReadExt as _, AsyncWriteExt as _},
-    net::{TcpListener, TcpStream},
+    io::{self, AsyncBufRead, AsyncWrite},
+    net::TcpListener,
+    sync::oneshot,
     task::JoinHandle,
+    time::{interval, sleep, timeout},
 };
 
-use crate::{addr::Addr, request::Filter};
+use crate::{
+    audit::{Audit, AuditSink},
+    auth::{self, Authenticator, Identity},
+    caps::{ConnectionCaps, ConnectionLimits},
+    egress::Egress,
+    filter::{ConnectionContext, Filter, FilterResult, Policy, Refused},
+    guard::IpNet,
+    handle::{AbortOnDrop, Connections, ServerHandle},
+    http,
+    metrics::{Metrics, MetricsCallback, MetricsSnapshot},
+    peek::HostPeeking,
+    ratelimit::{RateLimiter, RateLimits},
+    resolve::Resolver,
+    socks4,
+    timeouts::Timeouts,
+    transport::{self, ClientStream, ListenAddr, Listener, Transport},
+    upstream::Upstream,
+};
 
 use super::Error;
 use super::Request;
 
-pub struct Server<'a> {
-    listener: TcpListener,
-    filters: Vec<Box<Filter<'a>>>,
+/// How long [`Server::serve`] waits after the first failed accept, doubled
+/// with every failure in a row up to [`MAX_ACCEPT_BACKOFF`].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A cheap to clone handle to a listening proxy server.
+///
+/// Besides SOCKS5 it understands HTTP proxy requests on the same port, both
+/// `CONNECT` tunnels and plain requests in absolute form, as well as SOCKS4
+/// and SOCKS4a. SOCKS4 can't authenticate, so it is refused once
+/// [`Server::set_authenticator`] is used.
+///
+/// Configuration changes such as [`Server::add_filter`] only apply to the
+/// handle they are made on and to clones made from it afterwards.
+#[derive(Clone)]
+pub struct Server {
+    listeners: Arc<[Box<dyn Listener>]>,
+    config: Arc<Config>,
 }
 
-#[derive(PartialEq, Eq)]
-pub enum FilterResult {
-    Allow,
-    Deny,
+enum Bind {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf, u32),
+    Listener(Box<dyn Listener>),
 }
 
-impl<'a> Server<'a> {
-    /// get_forwarding_server should return the address
-    pub async fn new() -> io::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:0").await?;
-        Ok(Self {
-            filters: Vec::new(),
-            listener,
+/// Picks what a [`Server`] listens on, see [`Server::builder`]. Clients of
+/// every listener are served alike.
+#[derive(Default)]
+pub struct ServerBuilder {
+    binds: Vec<Bind>,
+}
+
+impl ServerBuilder {
+    /// Listens on `addr`, port 0 picks a free one.
+    pub fn tcp(mut self, addr: SocketAddr) -> Self {
+        self.binds.push(Bind::Tcp(addr));
+        self
+    }
+
+    /// Listens on a Unix domain socket at `path` with `mode` as its
+    /// permissions, `0o600` lets only the current user connect. A socket
+    /// left behind at `path` is replaced, and the socket is removed again
+    /// once the server and its clones are dropped.
+    ///
+    /// The permissions are set right after binding, put the socket in a
+    /// directory only the user can enter to close that gap too.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(mut self, path: P, mode: u32) -> Self {
+        self.binds.push(Bind::Unix(path.into(), mode));
+        self
+    }
+
+    /// Takes clients from any other [`Listener`], such as a
+    /// [`MemoryListener`](crate::MemoryListener).
+    pub fn listener<L: Listener>(mut self, listener: L) -> Self {
+        self.binds.push(Bind::Listener(Box::new(listener)));
+        self
+    }
+
+    /// Binds every listener, or a free port on `127.0.0.1` if none were
+    /// given.
+    pub async fn build(self) -> io::Result<Server> {
+        let mut binds = self.binds;
+        if binds.is_empty() {
+            binds.push(Bind::Tcp((std::net::Ipv4Addr::LOCALHOST, 0).into()));
+        }
+        let mut listeners = Vec::<Box<dyn Listener>>::new();
+        for bind in binds {
+            listeners.push(match bind {
+                Bind::Tcp(addr) => Box::new(TcpListener::bind(addr).await?),
+                #[cfg(unix)]
+                Bind::Unix(path, mode) => {
+                    Box::new(transport::UnixSocket::bind(path, mode)?)
+                }
+                Bind::Listener(listener) => listener,
+            });
+        }
+        Ok(Server {
+            listeners: listeners.into(),
+            config: Arc::default(),
         })
     }
+}
 
+#[derive(Clone, Default)]
+pub(crate) struct Config {
+    pub policy: Arc<Policy>,
+    pub authenticator: Option<Arc<Authenticator>>,
+    pub timeouts: Timeouts,
+    /// Shared with clones made after configuration changes too.
+    pub metrics: Arc<Metrics>,
+    pub metrics_callback: Option<(Duration, Arc<MetricsCallback>)>,
+    /// Shared with clones like the metrics, until limits are set again.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Shared the same way as the rate limiter.
+    pub caps: Arc<ConnectionCaps>,
+    pub audit_sinks: Arc<[Arc<dyn AuditSink>]>,
+    pub host_peeking: HostPeeking,
+}
+
+impl Server {
+    /// Listens on a free port on `127.0.0.1`.
+    pub async fn new() -> io::Result<Self> {
+        Self::builder().build().await
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address of the first TCP listener.
+    ///
+    /// # Panics
+    ///
+    /// If the server has no TCP listener.
     pub fn addr(&self) -> SocketAddr {
-        self.listener
-            .local_addr()
-            .expect("listener to have an address")
+        tcp_addr(&self.listen_addrs())
     }
 
+    /// The port of [`Server::addr`].
     pub fn port(&self) -> u16 {
-        let addr = self
-            .listener
-            .local_addr()
-            .expect("listener to have an address");
-        addr.port()
+        self.addr().port()
     }
 
+    /// Every address the server listens on, in the order the listeners were
+    /// added.
+    pub fn listen_addrs(&self) -> Vec<ListenAddr> {
+        self.listeners.iter().map(|l| l.listen_addr()).collect()
+    }
+
+    /// Waits for the next client and handles it on its own task. The returned
+    /// handle finishes once the client's proxy does.
+    ///
+    /// Once as many clients are in their handshake as the connection limits
+    /// allow in total, this waits for one of them to finish first.
     pub async fn poll(&self) -> Result<JoinHandle<()>, Error> {
-        let Ok(stream) = self.listener.accept().await else {
-            info!("connection failed.");
-            return Err(Error::Internal("connection failed"));
+        let permit = self.config.caps.handshake_permit().await;
+        let stream = match transport::accept_any(&self.listeners).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                info!("connection failed.");
+                return Err(Error::Io(e));
+            }
         };
-        self.accept(stream.0).await
+        let mut stream = ClientStream::new(stream);
+        let peer = stream.peer();
+        let server = self.clone();
+        Ok(tokio::spawn(async move {
+            let accepted = async {
+                let req = server.handshake(&mut stream).await;
+                drop(permit);
+                req?.handle(stream).await
+            };
+            let handle = match accepted.await {
+                Ok(handle) => handle,
+                Err(err) => return debug!("client {peer} failed: {err}"),
+            };
+            // the proxy doesn't need the listener, let it close on shutdown
+            drop(server);
+            let _abort_on_drop = AbortOnDrop::new(&handle);
+            let _ = handle.await;
+        }))
     }
 
-    pub async fn negotiate_auth(stream: &mut TcpStream) -> Result<(), Error> {
-        let _ver = stream.read_u8().await?;
-        let method_ct = stream.read_u8().await?;
-        let mut method_list = vec![0u8; method_ct.into()];
-        stream.read_exact(&mut method_list[..]).await?;
-
-        let mut no_auth_found = false;
-        trace!("{} auths supported: {:02X?}", method_ct, method_list);
-        for method in method_list {
-            if method == 0x00 {
-                no_auth_found = true
+    /// Accepts connections until `shutdown` resolves. The listener is closed
+    /// once this returns and no other clones of the server are left; the
+    /// connections which are still running are handed back so the caller can
+    /// decide whether to drain or abort them.
+    ///
+    /// Accepting fails while the process is out of file descriptors and the
+    /// like, so each failure in a row waits longer before the next try.
+    pub async fn serve<S: Future<Output = ()>>(
+        self,
+        shutdown: S,
+    ) -> Connections {
+        let mut connections = Connections::default();
+        let reporter =
+            self.config
+                .metrics_callback
+                .clone()
+                .map(|(period, callback)| {
+                    let metrics = self.config.metrics.clone();
+                    tokio::spawn(async move {
+                        let mut interval = interval(period);
+                        loop {
+                            interval.tick().await;
+                            callback(&metrics.snapshot());
+                        }
+                    })
+                });
+        let _stop_reporting = reporter.as_ref().map(AbortOnDrop::new);
+        tokio::pin!(shutdown);
+        let mut backoff = None;
+        loop {
+            let res = tokio::select! {
+                _ = &mut shutdown => break,
+                res = self.poll() => res,
+            };
+            let delay = match res {
+                Ok(handle) => {
+                    backoff = None;
+                    connections.push(handle);
+                    continue;
+                }
+                Err(err) => {
+                    let delay = backoff.map_or(MIN_ACCEPT_BACKOFF, |d| {
+                        MAX_ACCEPT_BACKOFF.min(d * 2)
+                    });
+                    error!("{err}, accepting again in {delay:?}");
+                    *backoff.insert(delay)
+                }
+            };
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = sleep(delay) => {}
             }
         }
-        if !no_auth_found {
-            stream.write_all(&[0x05, 0xFF]).await?;
-            // returning drops the stream, shuts it down
-            return Err(Error::InvalidAuth);
-        }
+        connections
+    }
 
-        // reply with version 0x05, protocol NO AUTHENTICATION REQUIRED
-        stream.write_all(&[0x05, 0x00]).await?;
+    /// Negotiates the authentication method with a freshly connected client
+    /// and returns who they authenticated as.
+    ///
+    /// Nothing past the client's last auth message is taken off `stream`, so
+    /// whatever follows it can be read from the same buffer.
+    pub async fn negotiate_auth<S: AsyncBufRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Identity, Error> {
+        auth::negotiate(stream, self.config.authenticator.as_deref()).await
+    }
 
-        Ok(())
+    /// Requires clients to log in with a username and password (RFC 1929)
+    /// which `authenticator` accepts. The username becomes the [`Identity`]
+    /// passed to every filter.
+    pub fn set_authenticator<
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    >(
+        &mut self,
+        authenticator: F,
+    ) {
+        Arc::make_mut(&mut self.config).authenticator =
+            Some(Arc::new(authenticator));
     }
 
     /// By default all requests are passed through.
-    /// If any filter returns false then the request will be blocked.
-    pub fn add_filter<'b: 'a, F: Fn(&Addr) -> FilterResult + 'b>(
+    /// Filters run in the order they were added, the first one to deny a
+    /// request blocks it.
+    pub fn add_filter<
+        F: Fn(&ConnectionContext) -> FilterResult + Send + Sync + 'static,
+    >(
         &mut self,
         filter: F,
     ) {
-        self.filters.push(Box::new(filter));
+        self.add_async_filter(filter);
+    }
+
+    /// Like [`Server::add_filter`] for anything implementing [`Filter`],
+    /// which lets the filter await while deciding.
+    pub fn add_async_filter<F: Filter + 'static>(&mut self, filter: F) {
+        self.policy_mut().filters.push(Arc::new(filter));
+    }
+
+    /// Turns the [`PrivateNetworkGuard`] on or off, it is on by default.
+    pub fn set_private_network_guard(&mut self, enabled: bool) {
+        self.policy_mut().guard.set_enabled(enabled);
+    }
+
+    /// Lets the private network guard through to `net`, optionally only on
+    /// `port`.
+    pub fn allow_private_network(&mut self, net: IpNet, port: Option<u16>) {
+        self.policy_mut().guard.allow(net, port);
+    }
+
+    /// Replaces how domains are resolved, by default the system resolver is
+    /// asked and its answers are cached.
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.policy_mut().resolver = Arc::new(resolver);
+    }
+
+    /// Sends outgoing CONNECTs through another proxy, filters can pick a
+    /// different one per connection with [`FilterResult::RouteVia`].
+    ///
+    /// Domains are resolved by the upstream, so the [`PrivateNetworkGuard`]
+    /// can't stop one which points at a private network on its side.
+    pub fn set_upstream(&mut self, upstream: Upstream) {
+        self.policy_mut().upstream = upstream;
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.config.timeouts
+    }
+
+    /// Replaces the default [`Timeouts`], filters can still override them
+    /// per connection.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Arc::make_mut(&mut self.config).timeouts = timeouts;
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.config.rate_limiter.limits().clone()
+    }
+
+    /// Throttles bytes and new connections globally, per identity, per
+    /// destination and per class. The buckets start out full again.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        Arc::make_mut(&mut self.config).rate_limiter =
+            Arc::new(RateLimiter::new(limits));
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.config.caps.limits()
+    }
+
+    /// Caps how many connections may be open at once, in total, per identity
+    /// and per destination. Connections which are already open don't count
+    /// towards the new caps.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        Arc::make_mut(&mut self.config).caps =
+            Arc::new(ConnectionCaps::new(limits));
+    }
+
+    /// Sends an [`AuditRecord`](crate::AuditRecord) of every connection
+    /// attempt to `sink`, including clients which fail their handshake.
+    pub fn add_audit_sink<S: AuditSink + 'static>(&mut self, sink: S) {
+        let config = Arc::make_mut(&mut self.config);
+        let mut sinks = config.audit_sinks.to_vec();
+        sinks.push(Arc::new(sink));
+        config.audit_sinks = sinks.into();
+    }
+
+    /// Lets filters see which host a CONNECT to a bare IP is really for.
+    ///
+    /// The client is told it is connected before anything is dialed, then
+    /// the SNI of its TLS ClientHello or the `Host` header of its HTTP
+    /// request is run through the filters again and the connection goes to
+    /// wherever that host resolves to, so a made up name can't smuggle
+    /// traffic to the IP. What was read is sent on first.
+    ///
+    /// Clients which send nothing, such as those of protocols where the
+    /// server speaks first, are waited on for the handshake timeout. Refusals
+    /// after the client was told it is connected just close the connection.
+    pub fn set_host_peeking(&mut self, mode: HostPeeking) {
+        Arc::make_mut(&mut self.config).host_peeking = mode;
+    }
+
+    /// Switches which destinations may be reached at all. Unlike the other
+    /// settings this applies to every clone of the server right away, and
+    /// running connections to destinations which aren't permitted anymore
+    /// are closed.
+    pub fn set_egress(&self, egress: Egress) {
+        self.config.policy.kill_switch.set_egress(egress);
+    }
+
+    pub fn egress(&self) -> Egress {
+        self.config.policy.kill_switch.egress()
+    }
+
+    /// Cuts `identity` off the network, or lets it back on. Takes effect
+    /// like [`Server::set_egress`].
+    pub fn set_offline(&self, identity: &Identity, offline: bool) {
+        self.config
+            .policy
+            .kill_switch
+            .set_offline(identity, offline);
+    }
+
+    pub fn is_offline(&self, identity: &Identity) -> bool {
+        self.config.policy.kill_switch.is_offline(identity)
+    }
+
+    /// Counters of every connection this server and its clones handled.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.config.metrics.snapshot()
+    }
+
+    /// Calls `callback` with a fresh [`MetricsSnapshot`] every `period` while
+    /// the server is serving.
+    pub fn on_metrics<F: Fn(&MetricsSnapshot) + Send + Sync + 'static>(
+        &mut self,
+        period: Duration,
+        callback: F,
+    ) {
+        Arc::make_mut(&mut self.config).metrics_callback =
+            Some((period, Arc::new(callback)));
     }
 
-    pub async fn accept(
+    fn policy_mut(&mut self) -> &mut Policy {
+        Arc::make_mut(&mut Arc::make_mut(&mut self.config).policy)
+    }
+
+    /// Handles a client which connected some other way, over any
+    /// [`Transport`]. Returns once the proxy is running.
+    pub async fn accept<T: Transport>(
         &self,
-        mut stream: TcpStream,
+        stream: T,
     ) -> Result<JoinHandle<()>, Error> {
-        Self::negotiate_auth(&mut stream).await?;
-
-        let req = Request::from_stream(&mut stream, &self.filters).await?;
+        let mut stream = ClientStream::new(Box::new(stream));
+        let req = self.handshake(&mut stream).await?;
         req.handle(stream).await
     }
+
+    /// Reads the client's request in whichever protocol it speaks, within
+    /// the handshake timeout.
+    async fn handshake(
+        &self,
+        stream: &mut ClientStream,
+    ) -> Result<Request<'_>, Error> {
+        let handshake = async {
+            // SOCKS greetings start with their version, HTTP requests with
+            // a method name
+            match stream.peek().await? {
+                0x05 => {
+                    let identity = self.negotiate_auth(stream).await?;
+                    Request::from_stream(stream, identity, &self.config).await
+                }
+                0x04 => {
+                    let authenticator = self.config.authenticator.as_deref();
+                    let req = socks4::handshake(stream, authenticator).await?;
+                    Request::from_socks4(stream, req, &self.config)
+                }
+                b'A'..=b'Z' => {
+                    let authenticator = self.config.authenticator.as_deref();
+                    let (identity, req) =
+                        http::handshake(stream, authenticator).await?;
+                    Request::from_http(stream, identity, req, &self.config)
+                }
+                _ => Err(Error::VersionMismatch),
+            }
+        };
+        let req = match timeout(self.config.timeouts.handshake, handshake).await
+        {
+            Ok(req) => req,
+            Err(_) => Err(Error::TtlExpired),
+        };
+        let e = match req {
+            Ok(req) => return Ok(req),
+            Err(e) => e,
+        };
+        self.config.metrics.deny(&e, None, None);
+        let refused = Refused::from(e);
+        let sinks = self.config.audit_sinks.clone();
+        // whatever the client was answered with is up to its protocol
+        Audit::new(sinks, stream.peer()).denied(&refused, None);
+        Err(refused.into())
+    }
+
+    /// Moves the server onto a background task which keeps accepting
+    /// connections until [`ServerHandle::shutdown`] is called or the handle is
+    /// dropped.
+    pub fn spawn(self) -> ServerHandle {
+        let addrs = self.listen_addrs();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(self.serve(async {
+            let _ = stopped.await;
+        }));
+        ServerHandle::new(addrs, stop, task)
+    }
+}
+
+/// The first TCP address in `addrs`, panicking without one.
+pub(crate) fn tcp_addr(addrs: &[ListenAddr]) -> SocketAddr {
+    addrs
+        .iter()
+        .find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            _ => None,
+        })
+        .expect("server to listen on TCP")
 }
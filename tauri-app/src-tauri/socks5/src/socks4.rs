@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::{Addr, Cmd, Error, auth::Authenticator};
+
+const GRANTED: u8 = 0x5A;
+const REJECTED: u8 = 0x5B;
+/// Strictly "the client program and identd report different user-ids", the
+/// closest SOCKS4 has to a failed login.
+const USERID_MISMATCH: u8 = 0x5D;
+
+/// Longest USERID or domain accepted.
+const MAX_FIELD: usize = 255;
+
+/// A SOCKS4 or SOCKS4a request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Socks4Request {
+    pub cmd: Cmd,
+    pub addr: Addr,
+    /// Whatever the client claims to be, SOCKS4 has no way to check it.
+    pub userid: String,
+}
+
+/// Reads a request. SOCKS4 can't carry a password, so when the server
+/// requires one the client is turned away.
+pub(crate) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    authenticator: Option<&Authenticator>,
+) -> Result<Socks4Request, Error> {
+    let req = read_request(stream).await;
+    if let Err(e) = &req {
+        let _ = reply(stream, Err(e)).await;
+    }
+    let req = req?;
+    trace!("SOCKS4 request from user id {:?}", req.userid);
+    if authenticator.is_some() {
+        reply(stream, Err(&Error::AuthFailed)).await?;
+        return Err(Error::AuthFailed);
+    }
+    Ok(req)
+}
+
+async fn read_request<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Socks4Request, Error> {
+    if stream.read_u8().await? != 0x04 {
+        return Err(Error::VersionMismatch);
+    }
+    let cmd = match stream.read_u8().await? {
+        0x01 => Cmd::Connect,
+        0x02 => Cmd::Bind,
+        cmd => return Err(Error::CmdNotSupported(cmd)),
+    };
+    let port = stream.read_u16().await?;
+    let ip = Ipv4Addr::from(stream.read_u32().await?);
+    let userid = read_field(stream).await?;
+    // SOCKS4a: 0.0.0.x with a non-zero x means a domain follows
+    let addr = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let domain = read_field(stream).await?;
+            Addr::try_from_domain(domain, port).await?
+        }
+        _ => Addr::from_ip_addr(IpAddr::V4(ip), port),
+    };
+    Ok(Socks4Request { cmd, addr, userid })
+}
+
+/// Reads a NUL terminated string.
+async fn read_field<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<String, Error> {
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => break,
+            _ if field.len() >= MAX_FIELD => {
+                return Err(Error::Malformed("SOCKS4 field too long"));
+            }
+            b => field.push(b),
+        }
+    }
+    Ok(String::from_utf8(field)?)
+}
+
+/// Sends a reply. SOCKS4 replies only have room for IPv4 addresses, anything
+/// else is sent as zeros.
+pub(crate) async fn reply<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    res: Result<&Addr, &Error>,
+) -> Result<(), Error> {
+    let (ip, port) = match res {
+        Ok(Addr::Ip(IpAddr::V4(ip), port)) => (*ip, *port),
+        _ => (Ipv4Addr::UNSPECIFIED, 0),
+    };
+    let mut buf = vec![0x00, status(res.err())];
+    buf.extend(port.to_be_bytes());
+    buf.extend(ip.octets());
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// The reply code for a request which failed with `err`, or succeeded.
+pub(crate) fn status(err: Option<&Error>) -> u8 {
+    match err {
+        None => GRANTED,
+        Some(Error::AuthFailed | Error::InvalidAuth) => USERID_MISMATCH,
+        Some(_) => REJECTED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Socks4Request, read_request, reply};
+    use crate::{Addr, Cmd, error::Error};
+
+    #[tokio::test]
+    async fn parses_socks4_and_socks4a() {
+        let mut req: &[u8] = &[4, 1, 0, 80, 93, 184, 215, 14, b'b', b'o', 0];
+        assert_eq!(
+            read_request(&mut req).await.unwrap(),
+            Socks4Request {
+                cmd: Cmd::Connect,
+                addr: Addr::from_ip_addr([93, 184, 215, 14].into(), 80),
+                userid: "bo".into(),
+            }
+        );
+
+        let mut req = vec![4, 2, 1, 187, 0, 0, 0, 9, 0];
+        req.extend(b"example.com\0");
+        let req = read_request(&mut req.as_slice()).await.unwrap();
+        assert_eq!(req.cmd, Cmd::Bind);
+        assert_eq!(req.addr, Addr::Domain("example.com".into(), 443));
+
+        let mut req: &[u8] = &[4, 3, 0, 80, 1, 2, 3, 4, 0];
+        assert!(matches!(
+            read_request(&mut req).await,
+            Err(Error::CmdNotSupported(0x03))
+        ));
+    }
+
+    #[tokio::test]
+    async fn replies_with_socks4_status_codes() {
+        let mut buf = Vec::new();
+        let bound = Addr::from_ip_addr([10, 0, 0, 1].into(), 8080);
+        reply(&mut buf, Ok(&bound)).await.unwrap();
+        reply(&mut buf, Err(&Error::BreaksRuleset)).await.unwrap();
+        reply(&mut buf, Err(&Error::AuthFailed)).await.unwrap();
+        assert_eq!(
+            buf,
+            [
+                0, 0x5A, 0x1F, 0x90, 10, 0, 0, 1, //
+                0, 0x5B, 0, 0, 0, 0, 0, 0, //
+                0, 0x5D, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+    }
+}
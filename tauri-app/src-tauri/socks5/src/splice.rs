@@ -0,0 +1,183 @@
+use std::{
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+    ptr,
+};
+
+use tokio::{
+    io::{self, Interest},
+    net::TcpStream,
+};
+
+/// Most a single splice moves, the default capacity of a pipe.
+const CHUNK: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+        // SAFETY: pipe2 writes two descriptors into an array of two
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both were just opened and belong to nothing else
+        let (read, write) = unsafe {
+            (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))
+        };
+        Ok(Self { read, write })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: the descriptors outlive the call, null offsets make it use
+    // and advance the current ones
+    let moved = unsafe {
+        libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags)
+    };
+    match moved {
+        ..0 => Err(io::Error::last_os_error()),
+        moved => Ok(moved as usize),
+    }
+}
+
+/// Moves everything `from` sends on to `to` through a pipe, then shuts down
+/// `to` for writing. `read` and `written` are called with every chunk as it
+/// leaves `from` and arrives at `to`.
+async fn one_way(
+    from: &TcpStream,
+    to: &TcpStream,
+    read: impl Fn(u64),
+    written: impl Fn(u64),
+) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0;
+    loop {
+        from.readable().await?;
+        let fill = || splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK);
+        let filled = match from.try_io(Interest::READABLE, fill) {
+            Ok(0) => break,
+            Ok(filled) => filled,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        read(filled as u64);
+        // the pipe is drained before the next fill, so that never blocks
+        let mut left = filled;
+        while left > 0 {
+            to.writable().await?;
+            let drain = || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left);
+            match to.try_io(Interest::WRITABLE, drain) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(drained) => {
+                    left -= drained;
+                    written(drained as u64);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += filled as u64;
+    }
+    // SAFETY: the socket stays open for the call
+    if unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(total)
+}
+
+/// Relays between two sockets without copying through user space, like
+/// [`tokio::io::copy_bidirectional`] returning what went from `remote` to
+/// `client` and back.
+///
+/// `up` and `down` see what is read from and written to the client, the
+/// same bytes [`Tracker::count`](crate::metrics::Tracker::count) sees.
+/// `active` is called whenever anything moves.
+pub(crate) async fn relay(
+    remote: &TcpStream,
+    client: &TcpStream,
+    up: impl Fn(u64),
+    down: impl Fn(u64),
+    active: impl Fn(),
+) -> io::Result<(u64, u64)> {
+    let downstream = one_way(
+        remote,
+        client,
+        |_| active(),
+        |len| {
+            down(len);
+            active();
+        },
+    );
+    let upstream = one_way(
+        client,
+        remote,
+        |len| {
+            up(len);
+            active();
+        },
+        |_| active(),
+    );
+    tokio::try_join!(downstream, upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::relay;
+
+    /// Both ends of a loopback connection.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_and_counts_every_byte() {
+        let (mut client, proxy_client) = pair().await;
+        let (proxy_remote, mut remote) = pair().await;
+        let (up, down) = (AtomicU64::new(0), AtomicU64::new(0));
+        let relayed = relay(
+            &proxy_remote,
+            &proxy_client,
+            |len| {
+                up.fetch_add(len, Ordering::Relaxed);
+            },
+            |len| {
+                down.fetch_add(len, Ordering::Relaxed);
+            },
+            || {},
+        );
+
+        let sent = (0..1 << 20).map(|i| i as u8).collect::<Vec<_>>();
+        let peers = async {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            assert!(received == sent);
+            remote.write_all(b"bye").await.unwrap();
+            remote.shutdown().await.unwrap();
+            let mut answer = Vec::new();
+            client.read_to_end(&mut answer).await.unwrap();
+            assert_eq!(answer, b"bye");
+        };
+        let (relayed, ()) = tokio::join!(relayed, peers);
+        assert_eq!(relayed.unwrap(), (3, 1 << 20));
+        assert_eq!(up.into_inner(), 1 << 20);
+        assert_eq!(down.into_inner(), 3);
+    }
+}
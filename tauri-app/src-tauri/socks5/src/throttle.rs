@@ -0,0 +1,251 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep, sleep_until},
+};
+
+/// A bucket several connections draw from.
+pub(crate) type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Refills at `rate` tokens per second up to one second's worth.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn shared(rate: u64) -> SharedBucket {
+        Arc::new(Mutex::new(Self::new(rate)))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Whole tokens which can be taken right now.
+    pub fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens.max(0.0) as u64
+    }
+
+    pub fn consume(&mut self, tokens: u64) {
+        self.tokens -= tokens as f64;
+    }
+
+    /// Whether the bucket has refilled completely, in which case it is no
+    /// different from a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.available(now) >= self.rate
+    }
+
+    /// When at least one token will be available again.
+    pub fn next_refill(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        // rounded up a little so the bucket is never polled just short of it
+        self.last
+            + Duration::from_secs_f64(missing / self.rate.max(1) as f64)
+            + Duration::from_millis(1)
+    }
+}
+
+/// Takes `tokens` from every bucket if each of them can spare that many, for
+/// datagrams which are dropped rather than held back. A full bucket lets
+/// even more through and goes into debt, so datagrams larger than a second's
+/// worth aren't dropped forever.
+pub(crate) fn try_take(buckets: &[SharedBucket], tokens: u64) -> bool {
+    let now = Instant::now();
+    let spare = buckets.iter().all(|bucket| {
+        let mut bucket = bucket.lock().expect("bucket to not be poisoned");
+        bucket.available(now) >= tokens || bucket.is_full(now)
+    });
+    if spare {
+        for bucket in buckets {
+            let mut bucket = bucket.lock().expect("bucket to not be poisoned");
+            bucket.consume(tokens);
+        }
+    }
+    spare
+}
+
+/// Limits how fast data can be read from the wrapped stream to what every one
+/// of its buckets allows. Writes pass straight through.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    buckets: Vec<SharedBucket>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, buckets: Vec<SharedBucket>) -> Self {
+        Self {
+            inner,
+            buckets,
+            sleep: Box::pin(sleep_until(Instant::now())),
+        }
+    }
+
+    /// Tokens every bucket can spare, or when the empty ones have refilled.
+    fn available(&self) -> Result<u64, Instant> {
+        let now = Instant::now();
+        let mut available = u64::MAX;
+        let mut refill = None;
+        for bucket in &self.buckets {
+            let mut bucket = bucket.lock().expect("bucket to not be poisoned");
+            match bucket.available(now) {
+                0 => refill = refill.max(Some(bucket.next_refill())),
+                n => available = available.min(n),
+            }
+        }
+        refill.map_or(Ok(available), Err)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.buckets.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let available = loop {
+            match this.available() {
+                Ok(available) => break available,
+                Err(refill) => {
+                    this.sleep.as_mut().reset(refill);
+                    ready!(this.sleep.as_mut().poll(cx));
+                }
+            }
+        };
+        let allowed = available.min(buf.remaining() as u64) as usize;
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        // SAFETY: the inner reader initialised and filled these bytes
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        for bucket in &this.buckets {
+            let mut bucket = bucket.lock().expect("bucket to not be poisoned");
+            bucket.consume(read as u64);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, duplex},
+        time::Instant,
+    };
+
+    use super::{Throttled, TokenBucket, try_take};
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_are_limited_to_the_rate() {
+        let (mut tx, rx) = duplex(8192);
+        tx.write_all(&[7u8; 3000]).await.unwrap();
+        let mut rx = Throttled::new(rx, vec![TokenBucket::shared(1000)]);
+
+        let start = Instant::now();
+        let mut buf = vec![0u8; 3000];
+        rx.read_exact(&mut buf).await.unwrap();
+        // the first second's worth is available straight away
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shared_buckets_split_the_rate() {
+        let shared = TokenBucket::shared(1000);
+        let (mut tx_a, rx_a) = duplex(8192);
+        let (mut tx_b, rx_b) = duplex(8192);
+        tx_a.write_all(&[7u8; 1500]).await.unwrap();
+        tx_b.write_all(&[7u8; 1500]).await.unwrap();
+        // the stricter of a stream's own and the shared bucket applies
+        let mut rx_a = Throttled::new(
+            rx_a,
+            vec![shared.clone(), TokenBucket::shared(5000)],
+        );
+        let mut rx_b = Throttled::new(rx_b, vec![shared]);
+
+        let start = Instant::now();
+        let mut buf_a = vec![0u8; 1500];
+        let mut buf_b = vec![0u8; 1500];
+        let (a, b) = tokio::join!(
+            rx_a.read_exact(&mut buf_a),
+            rx_b.read_exact(&mut buf_b)
+        );
+        a.unwrap();
+        b.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn datagrams_are_taken_whole_or_not_at_all() {
+        let small = TokenBucket::shared(1000);
+        let large = TokenBucket::shared(5000);
+        let buckets = [small.clone(), large.clone()];
+        assert!(try_take(&buckets, 600));
+        // the small bucket can't spare it, so neither gives anything
+        assert!(!try_take(&buckets, 600));
+        let now = Instant::now();
+        assert_eq!(large.lock().unwrap().available(now), 4400);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        // full buckets let larger datagrams through, then owe for them
+        assert!(try_take(&buckets, 1500));
+        assert!(!try_take(&buckets, 1));
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert!(try_take(&buckets, 1));
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, sleep, sleep_until},
+};
+
+use crate::filter::Limits;
+
+/// How long the server waits on clients and destinations.
+///
+/// Filters can override everything except `handshake` per connection with
+/// [`FilterResult::AllowWithLimits`](crate::FilterResult::AllowWithLimits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Connecting to the destination.
+    pub connect: Duration,
+    /// Negotiating authentication and reading the request, clients which
+    /// take longer are disconnected without a reply.
+    pub handshake: Duration,
+    /// A relayed connection is closed once no data went either way for this
+    /// long.
+    pub idle: Option<Duration>,
+    /// A relayed connection is closed once it has been open this long, no
+    /// matter how busy it is.
+    pub max_lifetime: Option<Duration>,
+    /// A BIND waiting for its inbound connection.
+    pub bind: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(30),
+            handshake: Duration::from_secs(10),
+            idle: Some(Duration::from_secs(300)),
+            max_lifetime: None,
+            bind: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Fills in whatever the filters left unset.
+    pub(crate) fn apply(&self, limits: Limits) -> Limits {
+        Limits {
+            connect_timeout: limits.connect_timeout.or(Some(self.connect)),
+            idle_timeout: limits.idle_timeout.or(self.idle),
+            max_lifetime: limits.max_lifetime.or(self.max_lifetime),
+            bind_timeout: limits.bind_timeout.or(Some(self.bind)),
+            ..limits
+        }
+    }
+}
+
+/// Resolves once `duration` has passed, never if there is none.
+pub(crate) async fn after(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Remembers when data last went through any of the streams it tracks.
+#[derive(Debug)]
+pub(crate) struct IdleTimer {
+    start: Instant,
+    /// Milliseconds after `start`.
+    last: AtomicU64,
+}
+
+impl IdleTimer {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn track<S>(&self, inner: S) -> Active<'_, S> {
+        Active { inner, timer: self }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn last_active(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once nothing went through for `idle`, never if there is no
+    /// limit.
+    pub async fn expired(&self, idle: Option<Duration>) {
+        let Some(idle) = idle else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = self.last_active() + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Resets its [`IdleTimer`] whenever data is read from the wrapped stream.
+/// Every byte relayed is read from one of the two sides, so wrapping both
+/// covers traffic in either direction.
+pub(crate) struct Active<'a, S> {
+    inner: S,
+    timer: &'a IdleTimer,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Active<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            self.timer.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Active<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, duplex},
+        time::{Instant, sleep},
+    };
+
+    use super::IdleTimer;
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timer_resets_on_reads() {
+        let timer = IdleTimer::new();
+        let (mut tx, rx) = duplex(64);
+        let mut rx = timer.track(rx);
+        let idle = Some(Duration::from_secs(10));
+
+        let start = Instant::now();
+        let reader = async {
+            let mut buf = [0u8; 1];
+            for _ in 0..3 {
+                sleep(Duration::from_secs(5)).await;
+                tx.write_all(b"x").await.unwrap();
+                rx.read_exact(&mut buf).await.unwrap();
+            }
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = reader => unreachable!(),
+            _ = timer.expired(idle) => (),
+        }
+        // the last read happened after 15 seconds
+        assert_eq!(start.elapsed().as_secs(), 25);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    io::IoSlice,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::{
+    io::{self, AsyncBufRead, AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, mpsc},
+};
+
+use crate::filter::BoxFuture;
+
+/// Room each direction of an in-memory connection has before writes wait.
+const MEMORY_BUFFER: usize = 64 * 1024;
+/// How much of a client's stream is read ahead during the handshake.
+const READ_AHEAD: usize = 4096;
+
+/// A connection clients speak to the proxy over.
+///
+/// Transports without IP addresses, such as Unix sockets or in-memory
+/// pipes, leave the addresses unset. Their clients show up to filters as
+/// `0.0.0.0:0`, and the relays of their BINDs and UDP associates listen on
+/// loopback.
+pub trait Transport:
+    AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static
+{
+    /// Address of the client.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Address the client reached the proxy on.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The TCP socket underneath, which lets Linux relay to and from it
+    /// without copying.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for tokio::net::UnixStream {}
+
+impl Transport for DuplexStream {}
+
+impl Transport for Box<dyn Transport> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        (**self).as_tcp()
+    }
+}
+
+/// Where a [`Listener`] takes connections from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Memory,
+}
+
+/// Hands a server its clients, see [`ServerBuilder::listener`].
+///
+/// [`ServerBuilder::listener`]: crate::ServerBuilder::listener
+pub trait Listener: Send + Sync + 'static {
+    /// Waits for the next client. Dropping the future must not lose one.
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Transport>>>;
+
+    fn listen_addr(&self) -> ListenAddr;
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Transport>>> {
+        Box::pin(async move {
+            let (stream, _) = TcpListener::accept(self).await?;
+            Ok(Box::new(stream) as Box<dyn Transport>)
+        })
+    }
+
+    fn listen_addr(&self) -> ListenAddr {
+        let addr = self.local_addr().expect("listener to have an address");
+        ListenAddr::Tcp(addr)
+    }
+}
+
+/// A Unix domain socket which is removed again once the server is dropped.
+#[cfg(unix)]
+pub(crate) struct UnixSocket {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Listens at `path` with `mode` as its permissions. A socket left
+    /// behind there is replaced, anything else is not.
+    pub fn bind(path: PathBuf, mode: u32) -> io::Result<Self> {
+        use std::{
+            fs,
+            os::unix::fs::{FileTypeExt as _, PermissionsExt as _},
+        };
+
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is no socket", path.display()),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let socket = Self { listener, path };
+        fs::set_permissions(&socket.path, fs::Permissions::from_mode(mode))?;
+        Ok(socket)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocket {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Transport>>> {
+        Box::pin(async move {
+            let (stream, _) = self.listener.accept().await?;
+            Ok(Box::new(stream) as Box<dyn Transport>)
+        })
+    }
+
+    fn listen_addr(&self) -> ListenAddr {
+        ListenAddr::Unix(self.path.clone())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Takes clients which connect through a [`MemoryConnector`] without any
+/// networking, mostly for tests.
+pub struct MemoryListener {
+    incoming: Mutex<mpsc::Receiver<DuplexStream>>,
+}
+
+/// Opens in-memory connections to the server its [`MemoryListener`] was
+/// given to.
+#[derive(Clone)]
+pub struct MemoryConnector {
+    incoming: mpsc::Sender<DuplexStream>,
+}
+
+impl MemoryListener {
+    pub fn new() -> (Self, MemoryConnector) {
+        let (tx, rx) = mpsc::channel(16);
+        let listener = Self {
+            incoming: Mutex::new(rx),
+        };
+        (listener, MemoryConnector { incoming: tx })
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Transport>>> {
+        Box::pin(async move {
+            let mut incoming = self.incoming.lock().await;
+            match incoming.recv().await {
+                Some(stream) => Ok(Box::new(stream) as Box<dyn Transport>),
+                // nobody can connect anymore
+                None => std::future::pending().await,
+            }
+        })
+    }
+
+    fn listen_addr(&self) -> ListenAddr {
+        ListenAddr::Memory
+    }
+}
+
+impl MemoryConnector {
+    /// Connects to the server, failing once the listener is gone.
+    pub async fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = io::duplex(MEMORY_BUFFER);
+        match self.incoming.send(server).await {
+            Ok(()) => Ok(client),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "the memory listener is gone",
+            )),
+        }
+    }
+}
+
+/// Accepts the next client of whichever listener has one first.
+pub(crate) async fn accept_any(
+    listeners: &[Box<dyn Listener>],
+) -> io::Result<Box<dyn Transport>> {
+    let mut accepts = listeners
+        .iter()
+        .map(|listener| listener.accept())
+        .collect::<Vec<_>>();
+    std::future::poll_fn(|cx| {
+        for accept in &mut accepts {
+            if let Poll::Ready(res) = accept.as_mut().poll(cx) {
+                return Poll::Ready(res);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// A client's connection, read through a small buffer so handshakes don't
+/// take a read per field. Whatever is left in it after the handshake is
+/// read again before the rest of the stream.
+pub(crate) struct ClientStream {
+    inner: Box<dyn Transport>,
+    buf: Box<[u8]>,
+    /// The unread part of `buf`.
+    pos: usize,
+    filled: usize,
+}
+
+impl ClientStream {
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self {
+            inner,
+            buf: vec![0; READ_AHEAD].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Waits for the first byte without taking it off the stream.
+    pub async fn peek(&mut self) -> io::Result<u8> {
+        use tokio::io::AsyncBufReadExt as _;
+
+        match self.fill_buf().await?.first() {
+            Some(&byte) => Ok(byte),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// The client's address, `0.0.0.0:0` on transports without one.
+    pub fn peer(&self) -> SocketAddr {
+        self.inner
+            .peer_addr()
+            .unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, 0).into())
+    }
+
+    /// The TCP socket underneath, once the buffer was read empty.
+    #[cfg_attr(
+        not(all(target_os = "linux", feature = "splice")),
+        expect(dead_code)
+    )]
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self.pos < self.filled {
+            true => None,
+            false => self.inner.as_tcp(),
+        }
+    }
+
+    /// Where the client reached the proxy, loopback on transports without
+    /// addresses since their clients are on this machine.
+    pub fn local(&self) -> SocketAddr {
+        self.inner
+            .local_addr()
+            .unwrap_or_else(|| (Ipv4Addr::LOCALHOST, 0).into())
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // reads at least as large as the buffer, like the relay's, skip it
+        if self.pos == self.filled && buf.remaining() >= self.buf.len() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = available.len().min(buf.remaining());
+        buf.put_slice(&available[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for ClientStream {
+    fn poll_fill_buf(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.filled {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf))?;
+            this.filled = buf.filled().len();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.filled]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,542 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, trace, warn};
+use tokio::{
+    io::AsyncReadExt as _, net::UdpSocket, task::JoinSet, time::Instant,
+};
+
+pub use crate::codec::UdpHeader;
+use crate::{
+    Addr, Cmd, Error,
+    auth::Identity,
+    filter::{ConnectionContext, KILL_SWITCH, Policy},
+    metrics::Tracker,
+    ratelimit::Admission,
+    throttle::try_take,
+    transport::ClientStream,
+};
+
+/// Largest payload a UDP datagram can carry.
+const MAX_DATAGRAM: usize = 65_535;
+/// How long an incomplete fragment sequence is kept around. RFC 1928 requires
+/// at least 5 seconds.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Set on the FRAG field of the last fragment in a sequence.
+const END_OF_SEQUENCE: u8 = 0x80;
+/// How long the filters' decision on a destination is reused before its
+/// datagrams are run through them again.
+const DECISION_TTL: Duration = Duration::from_secs(30);
+/// Upper bound on the destinations a decision is kept for.
+const MAX_DECISIONS: usize = 1024;
+/// Destinations the filters may be deciding on at once, datagrams to any
+/// further new ones are dropped.
+const MAX_UNDECIDED: usize = 16;
+/// Datagrams held for a destination the filters are deciding on.
+const MAX_HELD: usize = 8;
+/// Upper bound on the destinations which may answer the client.
+const MAX_PEERS: usize = MAX_DECISIONS;
+
+/// Reassembly queue for fragmented datagrams from the client.
+#[derive(Default)]
+struct Reassembly {
+    addr: Option<Addr>,
+    fragments: BTreeMap<u8, Vec<u8>>,
+    started: Option<Instant>,
+}
+
+impl Reassembly {
+    /// Queues one datagram, returning a whole one once it is complete.
+    fn push(
+        &mut self,
+        header: UdpHeader,
+        payload: &[u8],
+    ) -> Option<(Addr, Vec<u8>)> {
+        if header.frag == 0x00 {
+            // standalone datagrams abandon any sequence in progress
+            self.reset();
+            return Some((header.addr, payload.to_vec()));
+        }
+        let position = header.frag & !END_OF_SEQUENCE;
+        let expired = self
+            .started
+            .is_some_and(|started| started.elapsed() > REASSEMBLY_TIMEOUT);
+        let restarted = self
+            .fragments
+            .last_key_value()
+            .is_some_and(|(highest, _)| position < *highest);
+        if expired || restarted {
+            self.reset();
+        }
+        if position == 0x01 {
+            self.addr = Some(header.addr);
+        }
+        self.started.get_or_insert_with(Instant::now);
+        self.fragments.insert(position, payload.to_vec());
+
+        if header.frag & END_OF_SEQUENCE == 0 {
+            return None;
+        }
+        let complete = self.fragments.keys().copied().eq(1..=position);
+        let addr = self.addr.take();
+        let fragments = std::mem::take(&mut self.fragments);
+        self.reset();
+        match (complete, addr) {
+            (true, Some(addr)) => {
+                Some((addr, fragments.into_values().flatten().collect()))
+            }
+            _ => {
+                debug!("dropping incomplete fragment sequence");
+                None
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.addr = None;
+        self.fragments.clear();
+        self.started = None;
+    }
+}
+
+/// The destinations the client sent to, only they may answer it. Once full,
+/// the one sent to least recently makes room.
+#[derive(Default)]
+struct Peers(HashMap<SocketAddr, Instant>);
+
+impl Peers {
+    fn insert(&mut self, peer: SocketAddr) {
+        if !self.0.contains_key(&peer) && self.0.len() >= MAX_PEERS {
+            let oldest = self.0.iter().min_by_key(|(_, sent)| **sent);
+            if let Some((&oldest, _)) = oldest {
+                self.0.remove(&oldest);
+            }
+        }
+        self.0.insert(peer, Instant::now());
+    }
+
+    fn contains(&self, peer: &SocketAddr) -> bool {
+        self.0.contains_key(peer)
+    }
+}
+
+/// Where the filters let a destination's datagrams go, `None` if they were
+/// denied.
+type Target = Option<SocketAddr>;
+
+/// The filters' decisions on the destinations of an association. Filters may
+/// take their time, asking the user say, so they run on tasks of their own
+/// and the relay holds the datagrams meanwhile.
+#[derive(Default)]
+struct Decisions {
+    decided: HashMap<Addr, (Target, Instant)>,
+    /// Datagrams waiting for the decision on their destination.
+    held: HashMap<Addr, Vec<Vec<u8>>>,
+    /// `None` when the kill switch refused, which isn't the filters'
+    /// decision and isn't kept.
+    evaluations: JoinSet<(Addr, Option<Target>)>,
+}
+
+impl Decisions {
+    /// The decision on `addr`, unless there's no recent one.
+    fn get(&self, addr: &Addr) -> Option<Target> {
+        match self.decided.get(addr) {
+            Some((target, at)) if at.elapsed() < DECISION_TTL => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Holds `payload` until `addr` is decided on, spawning `evaluation`
+    /// unless it is being decided on already. Returns whether there was room.
+    fn hold(
+        &mut self,
+        addr: Addr,
+        payload: Vec<u8>,
+        evaluation: impl Future<Output = Option<Target>> + Send + 'static,
+    ) -> bool {
+        if let Some(held) = self.held.get_mut(&addr) {
+            if held.len() >= MAX_HELD {
+                return false;
+            }
+            held.push(payload);
+            return true;
+        }
+        if self.held.len() >= MAX_UNDECIDED {
+            return false;
+        }
+        let decided = addr.clone();
+        self.evaluations
+            .spawn(async move { (decided, evaluation.await) });
+        self.held.insert(addr, vec![payload]);
+        true
+    }
+
+    /// Waits for the next decision, returning it along with its destination
+    /// and the datagrams held for it.
+    async fn next(&mut self) -> (Addr, Target, Vec<Vec<u8>>) {
+        let (addr, decided) = match self.evaluations.join_next().await {
+            Some(Ok(decided)) => decided,
+            // a filter panicked, which takes the relay down like it did
+            // when filters ran on it
+            Some(Err(e)) => std::panic::resume_unwind(e.into_panic()),
+            None => std::future::pending().await,
+        };
+        if self.decided.len() >= MAX_DECISIONS {
+            self.decided
+                .retain(|_, (_, at)| at.elapsed() < DECISION_TTL);
+            if self.decided.len() >= MAX_DECISIONS {
+                self.decided.clear();
+            }
+        }
+        let held = self.held.remove(&addr).unwrap_or_default();
+        let Some(target) = decided else {
+            return (addr, None, held);
+        };
+        self.decided.insert(addr.clone(), (target, Instant::now()));
+        (addr, target, held)
+    }
+}
+
+/// Relays datagrams for one UDP ASSOCIATE until its control connection closes.
+pub(crate) struct UdpRelay {
+    control: ClientStream,
+    /// Faces the client, its address is sent back as BND.ADDR.
+    client_socket: UdpSocket,
+    /// Sends to and receives from the destinations.
+    remote_v4: UdpSocket,
+    remote_v6: Option<UdpSocket>,
+    /// Where the client sends datagrams from, learned from the request or the
+    /// first datagram.
+    client: Option<SocketAddr>,
+    identity: Identity,
+    policy: Arc<Policy>,
+    tracker: Tracker,
+    /// Datagrams its buckets can't spare the bytes for are dropped.
+    admission: Admission,
+}
+
+impl UdpRelay {
+    pub async fn bind(
+        client_hint: Addr,
+        control: ClientStream,
+        identity: Identity,
+        policy: Arc<Policy>,
+        tracker: Tracker,
+        admission: Admission,
+    ) -> Result<Self, (Error, ClientStream)> {
+        let local_ip = control.local().ip();
+        let client_socket = match UdpSocket::bind((local_ip, 0)).await {
+            Ok(socket) => socket,
+            Err(e) => return Err((e.into(), control)),
+        };
+        let remote_v4 = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await
+        {
+            Ok(socket) => socket,
+            Err(e) => return Err((e.into(), control)),
+        };
+        let remote_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+        // clients which don't know their port yet send zeros
+        let client = match client_hint {
+            Addr::Ip(ip, port) if !ip.is_unspecified() && port != 0 => {
+                Some(SocketAddr::new(ip, port))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            control,
+            client_socket,
+            remote_v4,
+            remote_v6,
+            client,
+            identity,
+            policy,
+            tracker,
+            admission,
+        })
+    }
+
+    pub fn control(&mut self) -> &mut ClientStream {
+        &mut self.control
+    }
+
+    pub fn into_control(self) -> ClientStream {
+        self.control
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.client_socket.local_addr()?)
+    }
+
+    /// Marks the association as set up once the client knows the relay.
+    pub fn connected(&mut self) {
+        self.tracker.connected(None);
+    }
+
+    pub async fn run(mut self) {
+        // clients without an address are on this machine
+        let client_ip = match self.control.peer() {
+            peer if peer.ip().is_unspecified() => self.control.local().ip(),
+            peer => peer.ip(),
+        };
+        let mut reassembly = Reassembly::default();
+        let mut decisions = Decisions::default();
+        let mut peers = Peers::default();
+        let mut control_buf = [0u8; 64];
+        let mut client_buf = vec![0u8; MAX_DATAGRAM];
+        let mut remote_buf = vec![0u8; MAX_DATAGRAM];
+        let mut remote_v6_buf = vec![0u8; MAX_DATAGRAM];
+        // datagrams are checked one by one, this only ends the association
+        // once nothing at all may be reached anymore
+        let kill_switch = self.policy.kill_switch.clone();
+        let revoked = kill_switch.revoked(self.identity.clone(), None);
+        tokio::pin!(revoked);
+        loop {
+            tokio::select! {
+                _ = &mut revoked => {
+                    debug!("UDP associate closed by the kill switch");
+                    break;
+                },
+                res = self.control.read(&mut control_buf) => match res {
+                    Ok(0) | Err(_) => break,
+                    // the client isn't meant to send anything here
+                    Ok(_) => continue,
+                },
+                res = self.client_socket.recv_from(&mut client_buf) => {
+                    let (len, from) = match res {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("UDP relay receive error: {e}");
+                            break;
+                        }
+                    };
+                    if from.ip() != client_ip
+                        || self.client.is_some_and(|client| client != from)
+                    {
+                        trace!("dropping datagram from stranger {from}");
+                        continue;
+                    }
+                    self.client = Some(from);
+                    let datagram = &client_buf[..len];
+                    let forwarded = self.forward(
+                        datagram,
+                        &mut reassembly,
+                        &mut decisions,
+                        &mut peers,
+                    );
+                    if let Err(e) = forwarded.await {
+                        debug!("dropping datagram from {from}: {e}");
+                    }
+                },
+                (addr, target, held) = decisions.next() => {
+                    let Some(target) = target else { continue };
+                    // the kill switch may have closed while they were held
+                    let kill_switch = &self.policy.kill_switch;
+                    if !kill_switch.permits(&self.identity, Some(&addr)) {
+                        debug!("dropping datagrams held for {addr}");
+                        continue;
+                    }
+                    for payload in held {
+                        let sent = self.send(target, &payload, &mut peers);
+                        if let Err(e) = sent.await {
+                            debug!("dropping datagram to {target}: {e}");
+                        }
+                    }
+                },
+                res = self.remote_v4.recv_from(&mut remote_buf) => {
+                    self.reply(res, &remote_buf, &peers).await;
+                },
+                res = recv_opt(&self.remote_v6, &mut remote_v6_buf) => {
+                    self.reply(res, &remote_v6_buf, &peers).await;
+                },
+            }
+        }
+        debug!("UDP associate for {} closed", self.identity);
+    }
+
+    /// Sends a datagram from the client on to its destination, or holds it
+    /// until the filters decided on a destination they haven't seen lately.
+    async fn forward(
+        &self,
+        datagram: &[u8],
+        reassembly: &mut Reassembly,
+        decisions: &mut Decisions,
+        peers: &mut Peers,
+    ) -> Result<(), Error> {
+        let (header, payload) = UdpHeader::parse(datagram)?;
+        let Some((addr, payload)) = reassembly.push(header, payload) else {
+            return Ok(());
+        };
+        // the kill switch applies right away, decided on or not
+        if !self.policy.kill_switch.permits(&self.identity, Some(&addr)) {
+            return Err(Error::BreaksRuleset);
+        }
+        match decisions.get(&addr) {
+            Some(Some(target)) => {
+                return self.send(target, &payload, peers).await;
+            }
+            Some(None) => return Err(Error::BreaksRuleset),
+            None => {}
+        }
+        let ctx = ConnectionContext::new(
+            self.control.peer(),
+            Cmd::UdpAssociate,
+            self.identity.clone(),
+            addr.clone(),
+        );
+        let policy = self.policy.clone();
+        let to = addr.clone();
+        let evaluation = async move {
+            let decided = match policy.evaluate(ctx).await {
+                Ok(decision) => decision.ctx.target(),
+                Err(refused) if refused.by.as_deref() == Some(KILL_SWITCH) => {
+                    debug!("dropping datagrams to {to}: {}", refused.err);
+                    return None;
+                }
+                Err(refused) => Err(refused.into()),
+            };
+            let target = decided
+                .map_err(|e| debug!("dropping datagrams to {to}: {e}"))
+                .ok();
+            Some(target)
+        };
+        match decisions.hold(addr, payload, evaluation) {
+            true => Ok(()),
+            false => {
+                Err(Error::Internal("too many datagrams await the filters"))
+            }
+        }
+    }
+
+    /// Sends a datagram the filters let through on to `target`.
+    async fn send(
+        &self,
+        target: SocketAddr,
+        payload: &[u8],
+        peers: &mut Peers,
+    ) -> Result<(), Error> {
+        let socket = match target {
+            SocketAddr::V4(_) => &self.remote_v4,
+            SocketAddr::V6(_) => {
+                self.remote_v6.as_ref().ok_or(Error::NetworkUnreachable)?
+            }
+        };
+        if !try_take(&self.admission.up, payload.len() as u64) {
+            return Err(Error::RateLimited);
+        }
+        socket.send_to(payload, target).await?;
+        self.tracker.up(payload.len() as u64);
+        peers.insert(target);
+        Ok(())
+    }
+
+    /// Sends a datagram from a destination back to the client.
+    async fn reply(
+        &self,
+        res: std::io::Result<(usize, SocketAddr)>,
+        buf: &[u8],
+        peers: &Peers,
+    ) {
+        let (len, from) = match res {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("UDP relay receive error: {e}");
+                return;
+            }
+        };
+        // only answers from somewhere the client sent to are let through
+        let (Some(client), true) = (self.client, peers.contains(&from)) else {
+            trace!("dropping unsolicited datagram from {from}");
+            return;
+        };
+        if !try_take(&self.admission.down, len as u64) {
+            trace!("dropping datagram from {from} over the rate limits");
+            return;
+        }
+        let from = match from {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+                None => from,
+            },
+            SocketAddr::V4(_) => from,
+        };
+        let header = UdpHeader::new(Addr::from_ip_addr(from.ip(), from.port()));
+        let datagram = header.encapsulate(&buf[..len]);
+        match self.client_socket.send_to(&datagram, client).await {
+            Ok(_) => self.tracker.down(len as u64),
+            Err(e) => debug!("could not relay datagram to {client}: {e}"),
+        }
+    }
+}
+
+async fn recv_opt(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{MAX_PEERS, Peers, Reassembly, UdpHeader};
+    use crate::Addr;
+
+    fn header(frag: u8) -> UdpHeader {
+        UdpHeader {
+            frag,
+            addr: Addr::Domain("example.com".into(), 53),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_sent_to_least_recently_make_room() {
+        let mut peers = Peers::default();
+        let peer = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut send = async |port| {
+            peers.insert(peer(port));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        };
+        for port in 0..MAX_PEERS as u16 {
+            send(port).await;
+        }
+        // sending again keeps the first one around
+        send(0).await;
+        send(u16::MAX).await;
+        assert_eq!(peers.0.len(), MAX_PEERS);
+        assert!(peers.contains(&peer(0)));
+        assert!(!peers.contains(&peer(1)));
+        assert!(peers.contains(&peer(u16::MAX)));
+    }
+
+    #[test]
+    fn reassembles_fragments_in_order() {
+        let mut queue = Reassembly::default();
+        assert!(queue.push(header(1), b"he").is_none());
+        assert!(queue.push(header(2), b"ll").is_none());
+        let (addr, payload) = queue.push(header(0x83), b"o").unwrap();
+        assert!(matches!(addr, Addr::Domain(d, 53) if d == "example.com"));
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn drops_sequences_with_missing_fragments() {
+        let mut queue = Reassembly::default();
+        assert!(queue.push(header(1), b"he").is_none());
+        assert!(queue.push(header(0x83), b"o").is_none());
+        // a lower position starts a new sequence
+        assert!(queue.push(header(2), b"ll").is_none());
+        assert!(queue.push(header(1), b"he").is_none());
+        assert!(queue.push(header(0x82), b"llo").is_some());
+        // standalone datagrams pass straight through
+        let (_, payload) = queue.push(header(0), b"solo").unwrap();
+        assert_eq!(payload, b"solo");
+    }
+}
@@ -0,0 +1,142 @@
+use std::{fmt::Display, net::SocketAddr};
+
+use log::trace;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpStream,
+};
+
+use crate::{
+    Addr, Error,
+    client::Client,
+    connect::{CONNECTION_ATTEMPT_DELAY, happy_eyeballs},
+    filter::ConnectionContext,
+    resolve::Resolver,
+};
+
+/// A username and password for an upstream SOCKS5 proxy, see RFC 1929.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Where outgoing CONNECTs go.
+///
+/// Only CONNECT is chained, BIND and UDP ASSOCIATE always go direct.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Upstream {
+    /// Connect to the destination from this machine.
+    #[default]
+    Direct,
+    /// Ask another SOCKS5 proxy to connect. Domains are handed over without
+    /// being resolved here.
+    Socks5 {
+        addr: Addr,
+        credentials: Option<Credentials>,
+    },
+    /// Ask an HTTP proxy to open a tunnel with `CONNECT host:port`. Domains
+    /// are handed over without being resolved here.
+    HttpConnect { addr: Addr },
+}
+
+impl Upstream {
+    pub fn is_direct(&self) -> bool {
+        matches!(self, Upstream::Direct)
+    }
+
+    /// Opens a connection to the destination of `ctx`, through the upstream
+    /// if there is one. The upstream's own address is looked up with
+    /// `resolver` and is not subject to the private network guard.
+    pub(crate) async fn connect(
+        &self,
+        ctx: &ConnectionContext,
+        resolver: &dyn Resolver,
+    ) -> Result<TcpStream, Error> {
+        let proxy = match self {
+            Upstream::Direct => {
+                let targets = ctx.targets();
+                return happy_eyeballs(&targets, CONNECTION_ATTEMPT_DELAY)
+                    .await
+                    .map_err(Error::dialing);
+            }
+            Upstream::Socks5 { addr, .. } | Upstream::HttpConnect { addr } => {
+                addr
+            }
+        };
+        let port = proxy.port();
+        let proxy_addrs = proxy
+            .lookup(resolver)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect::<Vec<_>>();
+        let mut stream =
+            happy_eyeballs(&proxy_addrs, CONNECTION_ATTEMPT_DELAY).await?;
+        trace!("connecting to {:?} through {self}", ctx.addr);
+        match self {
+            Upstream::Direct => unreachable!("direct connections return early"),
+            Upstream::Socks5 { credentials, .. } => {
+                let client = Client::new(credentials.clone());
+                // the bound address is of no use to us
+                let (stream, _) =
+                    client.connect(stream, ctx.addr.clone()).await?;
+                Ok(stream)
+            }
+            Upstream::HttpConnect { .. } => {
+                http_connect(&mut stream, &ctx.addr).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Direct => f.write_str("direct"),
+            Upstream::Socks5 { addr, .. } => write!(f, "socks5 proxy {addr:?}"),
+            Upstream::HttpConnect { addr } => write!(f, "http proxy {addr:?}"),
+        }
+    }
+}
+
+/// Longest response head accepted from an HTTP proxy.
+const MAX_HTTP_HEAD: usize = 8192;
+
+/// Asks an HTTP proxy for a tunnel and reads its response.
+async fn http_connect(
+    stream: &mut TcpStream,
+    target: &Addr,
+) -> Result<(), Error> {
+    let authority = match target {
+        Addr::Ip(ip, port) => SocketAddr::new(*ip, *port).to_string(),
+        Addr::Domain(domain, port) => format!("{domain}:{port}"),
+        Addr::Null => return Err(Error::AddressTypeNotSupported),
+    };
+    let req =
+        format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // read byte by byte so nothing the destination sends early is lost
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD {
+            return Err(Error::Internal("HTTP proxy response too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status = head
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(Error::Internal("malformed HTTP proxy response"))?;
+    match status {
+        200..=299 => Ok(()),
+        403 | 407 => Err(Error::BreaksRuleset),
+        502 => Err(Error::HostUnreachable),
+        504 => Err(Error::TtlExpired),
+        _ => Err(Error::Internal("HTTP proxy refused the tunnel")),
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tauri::Url;
+
+/// What an app's webview logs in to the proxy with.
+struct Login {
+    token: String,
+    /// Labels of the windows the app runs in.
+    windows: Vec<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Keyed by docId.
+    logins: HashMap<String, Login>,
+    next_window: u64,
+}
+
+/// The apps running in a webview of their own. Each webview logs in to the
+/// proxy with the docId of its app as the username and a random token as
+/// the password, so the proxy can tell apps apart.
+#[derive(Clone, Default)]
+pub struct Apps(Arc<Mutex<Inner>>);
+
+impl Apps {
+    /// Sets up a new window for `doc_id`, returning its label and the proxy
+    /// URL its webview logs in with.
+    pub fn open(
+        &self,
+        doc_id: &str,
+        proxy_port: u16,
+    ) -> Result<(String, Url), String> {
+        let mut inner = self.lock();
+        let label = format!("app-{}", inner.next_window);
+        inner.next_window += 1;
+        let login = match inner.logins.entry(doc_id.to_string()) {
+            Entry::Occupied(login) => login.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Login {
+                token: token()?,
+                windows: Vec::new(),
+            }),
+        };
+        let mut url = Url::parse(&format!("socks5://127.0.0.1:{proxy_port}"))
+            .map_err(|e| e.to_string())?;
+        url.set_username(doc_id)
+            .and_then(|()| url.set_password(Some(&login.token)))
+            .map_err(|()| format!("{doc_id} can't be put in a proxy URL"))?;
+        login.windows.push(label.clone());
+        Ok((label, url))
+    }
+
+    /// Whether `token` is what the webviews of `doc_id` log in with.
+    pub fn check(&self, doc_id: &str, token: &str) -> bool {
+        let inner = self.lock();
+        inner.logins.get(doc_id).is_some_and(|login| {
+            // the same time whichever byte differs
+            login.token.len() == token.len()
+                && login
+                    .token
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+
+    /// Whether `doc_id` was ever opened.
+    pub fn is_known(&self, doc_id: &str) -> bool {
+        self.lock().logins.contains_key(doc_id)
+    }
+
+    /// The docId of the app running in the window labelled `label`.
+    pub fn doc_id(&self, label: &str) -> Option<String> {
+        let inner = self.lock();
+        inner
+            .logins
+            .iter()
+            .find(|(_, login)| login.windows.iter().any(|w| w == label))
+            .map(|(doc_id, _)| doc_id.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().expect("apps to not be poisoned")
+    }
+}
+
+/// 128 random bits, hex encoded.
+fn token() -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)
+        .map_err(|e| format!("could not make a proxy token: {e}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
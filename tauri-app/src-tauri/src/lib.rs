@@ -1,38 +1,238 @@
+pub mod apps;
+pub mod prompt;
 pub mod subdomain;
 
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Arc,
+    time::Duration,
+};
 
+use apps::Apps;
 use log::info;
-use tauri::{async_runtime::block_on, utils::config::{Csp, CspDirectiveSources}, Url};
+use prompt::{Choice, DecisionStore, Prompts};
+use socks5::AuditSink as _;
+use tauri::{async_runtime::block_on, utils::config::{Csp, CspDirectiveSources}, AppHandle, Emitter, Manager, WebviewWindow};
 
 #[derive(Default)]
 struct SandboxPort(Arc<u16>);
+struct ProxyPort(u16);
+/// Keeps the proxy accepting connections for as long as the app is running.
+struct ProxyServer(#[allow(dead_code)] socks5::ServerHandle);
+/// A clone of the running proxy, for switching the network at runtime.
+struct NetworkSwitch(socks5::Server);
+/// At most this many audit records wait for the writer, the ones past that
+/// are dropped rather than holding up the connections they are about. They
+/// wait in the queue until setup has opened the file, too.
+const MAX_QUEUED_ACTIVITY: usize = 1024;
 // remember to call `.manage(MyState::default())`
 #[tauri::command]
 fn get_sandbox_url(state: tauri::State<'_, SandboxPort>) -> String {
     format!("http://localhost:{}", state.0)
 }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    let socks_port = block_on(async {
-        let mut socks_server =
-            socks5::Server::new().await.expect("server to start");
-        socks_server.add_filter(|addr| {
-            println!("OHH");
+/// The docId of the app running in the window asking.
+#[tauri::command]
+fn get_app_doc_id(
+    window: WebviewWindow,
+    apps: tauri::State<'_, Apps>,
+) -> Result<String, String> {
+    apps.doc_id(window.label())
+        .ok_or_else(|| format!("no app runs in {}", window.label()))
+}
 
-            info!("filtering request to {addr:?}");
-            socks5::FilterResult::Allow
-        });
+/// Opens the app with `doc_id` in a window of its own.
+#[tauri::command]
+fn open_app(
+    doc_id: String,
+    app: AppHandle,
+    apps: tauri::State<'_, Apps>,
+    proxy_port: tauri::State<'_, ProxyPort>,
+) -> Result<(), String> {
+    open_app_window(&app, &apps, &doc_id, proxy_port.0)?;
+    Ok(())
+}
 
-        socks_server.port()
-    });
+#[tauri::command]
+fn answer_network_prompt(
+    id: u64,
+    choice: Choice,
+    prompts: tauri::State<'_, Prompts>,
+) -> Result<(), String> {
+    prompts.answer(id, choice)
+}
+
+#[tauri::command]
+fn set_egress(egress: socks5::Egress, switch: tauri::State<'_, NetworkSwitch>) {
+    info!("switching egress to {egress:?}");
+    switch.0.set_egress(egress);
+}
+
+#[tauri::command]
+fn get_egress(switch: tauri::State<'_, NetworkSwitch>) -> socks5::Egress {
+    switch.0.egress()
+}
+
+/// Takes the app with `doc_id` off the network, or puts it back on, closing
+/// whatever it has open. Its webviews log in to the proxy as `doc_id`, see
+/// [`Apps`].
+#[tauri::command]
+fn set_app_offline(
+    doc_id: String,
+    offline: bool,
+    apps: tauri::State<'_, Apps>,
+    switch: tauri::State<'_, NetworkSwitch>,
+) -> Result<(), String> {
+    if !apps.is_known(&doc_id) {
+        return Err(format!("no app was opened as {doc_id}"));
+    }
+    switch.0.set_offline(&socks5::Identity::User(doc_id), offline);
+    Ok(())
+}
+
+/// The app opened when the app starts.
+const FIRST_APP: &str = "webxdc-test/excalidraw";
 
+/// Builds a window running the app with `doc_id`, its webview logs in to the
+/// proxy as that app.
+fn open_app_window(
+    app: &AppHandle,
+    apps: &Apps,
+    doc_id: &str,
+    proxy_port: u16,
+) -> Result<WebviewWindow, String> {
+    let (label, proxy_url) = apps.open(doc_id, proxy_port)?;
+    let mut script_source = String::new();
+    if std::env::consts::OS == "android" {
+        script_source += r"
+        try {
+
+            for (let i = 0; i < 500; i++) {
+                new RTCPeerConnection()
+            }
+        } catch(err) {console.warn(err)}
+        "
+    }
+    // works for everything *but* android. For android we run Fill500
+    // in all documents immediately after they are created:
+    // for (let i = 0; i < 500; i++) {
+    //     new RTCPeerConnection()
+    // }
+    // This is because there is no way to run JS before a new document
+    // is returned to the caller on android.
+    // example exploitation which FILL500 mitigates for chromium browsers:
+    // document.body.innerHTML += `<iframe id=a></iframe>`
+    // new a.contentWindow.window.RTCPeerConnection()
+    // note that we must Fill500 before any other content is injected
+    // into the DOM. This is because iframes with the sandbox attribute
+    // spawn their own process and thus the 500 RTC limit resets for them.
+    // source & credit: https://delta.chat/en/2023-05-22-webxdc-security
+    const REPLACEMENT_SRC: &str =
+        r#"()=>{console.error("RTCPeerConnection not supported in a sandbox.")}"#;
+    script_source += format!(
+        r#"
+    window.RTCPeerConnection = {REPLACEMENT_SRC};
+    RTCPeerConnection = {REPLACEMENT_SRC};
+    try {{
+        window.webkitRTCPeerConnection = {REPLACEMENT_SRC};
+        webkitRTCPeerConnection = {REPLACEMENT_SRC};
+    }} catch (e){{}}
+     console.debug("replaced RTCPeerConnection")
+    "#,
+    )
+    .as_str();
+    #[allow(unused_mut)]
+    let mut window_builder = tauri::webview::WebviewWindowBuilder::new(
+        app,
+        label,
+        tauri::WebviewUrl::App("index.html".into()),
+    )
+    .initialization_script_for_all_frames(script_source)
+    // the app's docId and token, the proxy tells apps apart by them
+    .proxy_url(proxy_url)
+    .use_https_scheme(true)
+    // default behavior; good to make explicit
+    // .devtools(cfg!(debug_assertions));
+    .devtools(true);
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        window_builder = window_builder.allow_link_preview(false);
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use tauri::TitleBarStyle;
+
+        window_builder = window_builder.title_bar_style(TitleBarStyle::Transparent)
+    }
+    window_builder.build().map_err(|e| e.to_string())
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
     let sandbox_port = {
         let server = subdomain::Server::new();
         server.start();
         server.port()
     };
+
+    let (activity, mut queued_activity) =
+        tokio::sync::mpsc::channel::<socks5::AuditRecord>(MAX_QUEUED_ACTIVITY);
+    let apps = Apps::default();
+    // long enough to read the prompt, short enough that the page gives up
+    // on the request before the user has forgotten about it
+    let prompts = Prompts::new(Duration::from_secs(30));
+    let (network_switch, socks_server) = block_on(async {
+        let mut socks_server =
+            socks5::Server::new().await.expect("server to start");
+        // the sandbox pages themselves are served from localhost
+        for loopback in ["127.0.0.1", "::1"] {
+            socks_server.allow_private_network(
+                loopback.parse().expect("loopback to be a valid network"),
+                Some(sandbox_port),
+            );
+        }
+        // enough for page loads, not for a sandboxed app flooding the network
+        socks_server.set_rate_limits(socks5::RateLimits {
+            global: socks5::Rate {
+                connections: Some(100),
+                ..Default::default()
+            },
+            per_host: socks5::Rate {
+                connections: Some(20),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        // keeps a sandbox opening sockets in a loop from using up the app's
+        // file descriptors
+        socks_server.set_connection_limits(socks5::ConnectionLimits {
+            total: Some(256),
+            per_host: Some(32),
+            when_full: socks5::WhenFull::Wait {
+                max_waiting: 64,
+                timeout: Duration::from_secs(10),
+            },
+            ..Default::default()
+        });
+        // every app's webview logs in with its own docId, see `Apps`
+        socks_server.set_authenticator({
+            let apps = apps.clone();
+            move |doc_id, token| apps.check(doc_id, token)
+        });
+        // sandboxes resolving names themselves still get asked about by
+        // name
+        socks_server.set_host_peeking(socks5::HostPeeking::On);
+        // written out and sent to the network panel by the writer setup
+        // starts, off the proxy's workers
+        socks_server.add_audit_sink(move |record: &socks5::AuditRecord| {
+            let _ = activity.try_send(record.clone());
+        });
+        socks_server.add_async_filter(prompts.clone());
+
+        (socks_server.clone(), socks_server.spawn())
+    });
+    let socks_port = socks_server.port();
     let mut context = tauri::generate_context!();
     let init_policy = context
         .config()
@@ -56,7 +256,7 @@ pub fn run() {
     policy.entry("default-src".to_string()).or_insert(CspDirectiveSources::List(vec![])).push(format!("http://localhost:{sandbox_port}"));
 
     context.config_mut().app.security.csp = Some(Csp::DirectiveMap(policy.clone()));
-    fs::write("/Users/zphrs/Library/Logs/com.plexigraph.app/lol.log", format!("{init_policy_str:?}\n{policy:#?}")).unwrap();
+    info!("rewritten policy: {policy:?}");
 
     // context.config_mut().app.security.csp = Some(Csp::Policy(new_csp.clone()));
     tauri::Builder::default()
@@ -70,72 +270,43 @@ pub fn run() {
                 .level(log::LevelFilter::Info).build()
         )
         
-        .invoke_handler(tauri::generate_handler![get_sandbox_url])
+        .invoke_handler(tauri::generate_handler![
+            get_sandbox_url,
+            get_app_doc_id,
+            open_app,
+            answer_network_prompt,
+            set_egress,
+            get_egress,
+            set_app_offline
+        ])
         .manage(SandboxPort(sandbox_port.into()))
+        .manage(ProxyPort(socks_port))
+        .manage(apps.clone())
+        .manage(prompts.clone())
+        .manage(ProxyServer(socks_server))
+        .manage(NetworkSwitch(network_switch))
         .plugin(tauri_plugin_opener::init())
         .setup(move |app| {
-            let mut script_source = String::new();
-            if std::env::consts::OS == "android" {
-                script_source += r"
-                try {
-
-                    for (let i = 0; i < 500; i++) {
-                        new RTCPeerConnection()
-                    }
-                } catch(err) {console.warn(err)}
-                "
-            }
-            // works for everything *but* android. For android we run Fill500
-            // in all documents immediately after they are created:
-            // for (let i = 0; i < 500; i++) {
-            //     new RTCPeerConnection()
-            // }
-            // This is because there is no way to run JS before a new document
-            // is returned to the caller on android.
-            // example exploitation which FILL500 mitigates for chromium browsers:
-            // document.body.innerHTML += `<iframe id=a></iframe>`
-            // new a.contentWindow.window.RTCPeerConnection()
-            // note that we must Fill500 before any other content is injected
-            // into the DOM. This is because iframes with the sandbox attribute
-            // spawn their own process and thus the 500 RTC limit resets for them.
-            // source & credit: https://delta.chat/en/2023-05-22-webxdc-security
-            const REPLACEMENT_SRC: &str =
-                r#"()=>{console.error("RTCPeerConnection not supported in a sandbox.")}"#;
-            script_source += format!(
-                r#"
-            window.RTCPeerConnection = {REPLACEMENT_SRC};
-            RTCPeerConnection = {REPLACEMENT_SRC};
-            try {{
-                window.webkitRTCPeerConnection = {REPLACEMENT_SRC};
-                webkitRTCPeerConnection = {REPLACEMENT_SRC};
-            }} catch (e){{}}
-             console.debug("replaced RTCPeerConnection")
-            "#,
-            )
-            .as_str();
-            #[allow(unused_mut)]
-            let mut window_builder = tauri::webview::WebviewWindowBuilder::new(
-                app,
-                "label",
-                tauri::WebviewUrl::App("index.html".into()),
-            )
-            .initialization_script_for_all_frames(script_source)
-            .proxy_url(Url::parse(format!("socks5://127.0.0.1:{}", socks_port).as_str()).unwrap())
-            .use_https_scheme(true)
-            // default behavior; good to make explicit
-            // .devtools(cfg!(debug_assertions));
-            .devtools(true);
-            #[cfg(any(target_os = "macos", target_os = "ios"))]
-            {
-                window_builder = window_builder.allow_link_preview(false);
-            }
-            #[cfg(target_os = "macos")]
-            {
-                use tauri::TitleBarStyle;
-
-                window_builder = window_builder.title_bar_style(TitleBarStyle::Transparent)
-            }
-            let _window = window_builder.build()?;
+            let log_dir = app.path().app_log_dir()?;
+            fs::create_dir_all(&log_dir)?;
+            let audit_file = socks5::AuditFile::open(
+                log_dir.join("network.jsonl"),
+                5 * 1024 * 1024,
+                3,
+            )?;
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                while let Some(record) = queued_activity.blocking_recv() {
+                    audit_file.record(&record);
+                    let _ = handle.emit("network-activity", &record);
+                }
+            });
+            let data_dir = app.path().app_data_dir()?;
+            fs::create_dir_all(&data_dir)?;
+            let decisions =
+                DecisionStore::open(data_dir.join("network-decisions.json"))?;
+            prompts.attach(app.handle().clone(), decisions);
+            open_app_window(app.handle(), &apps, FIRST_APP, socks_port)?;
             Ok(())
         })
         .run(context)
@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use socks5::{filter::BoxFuture, ConnectionContext, Filter, FilterResult};
+use tauri::{AppHandle, Emitter};
+use tokio::{sync::oneshot, time::timeout};
+
+/// What the user answered a [`NetworkPrompt`] with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Choice {
+    AllowOnce,
+    AllowAlways,
+    Deny,
+}
+
+/// Sent to the UI as the `network-prompt` event. It is answered through the
+/// `answer_network_prompt` command with the same `id`, or taken back with a
+/// `network-prompt-expired` event carrying the `id` once it times out.
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkPrompt {
+    pub id: u64,
+    /// The docId of the app asking.
+    pub app: String,
+    pub host: String,
+    pub port: u16,
+}
+
+/// The destinations each app was allowed to reach for good, kept as JSON in
+/// the app data dir.
+pub struct DecisionStore {
+    path: PathBuf,
+    allowed: Mutex<HashMap<String, BTreeSet<String>>>,
+}
+
+impl DecisionStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let allowed = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            allowed: Mutex::new(allowed),
+        })
+    }
+
+    pub fn is_allowed(&self, app: &str, host: &str) -> bool {
+        let allowed = self.allowed.lock().expect("store to not be poisoned");
+        allowed.get(app).is_some_and(|hosts| hosts.contains(host))
+    }
+
+    /// Remembers `host` for `app` and writes the store out again.
+    pub fn allow(&self, app: &str, host: &str) -> io::Result<()> {
+        let mut allowed =
+            self.allowed.lock().expect("store to not be poisoned");
+        let hosts = allowed.entry(app.to_string()).or_default();
+        if !hosts.insert(host.to_string()) {
+            return Ok(());
+        }
+        // written next to the store first so a crash can't leave half of it
+        let json = serde_json::to_vec_pretty(&*allowed)?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// A prompt on screen and every connection waiting for its answer.
+struct Pending {
+    id: u64,
+    waiting: Vec<oneshot::Sender<Choice>>,
+}
+
+struct Inner {
+    /// Set up once the app has started, connections are denied before.
+    ui: OnceLock<(AppHandle, DecisionStore)>,
+    /// Keyed by app and host, so a page opening many connections to the same
+    /// host shows a single prompt.
+    pending: Mutex<HashMap<(String, String), Pending>>,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+/// A filter holding connections to destinations an app wasn't allowed to
+/// reach yet until the user decides, denying them after `timeout`.
+///
+/// Datagrams are asked about like connections, the proxy holds the first few
+/// to a destination while the prompt is open.
+#[derive(Clone)]
+pub struct Prompts(Arc<Inner>);
+
+impl Prompts {
+    pub fn new(timeout: Duration) -> Self {
+        Self(Arc::new(Inner {
+            ui: OnceLock::new(),
+            pending: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            timeout,
+        }))
+    }
+
+    /// Starts prompting through `app`.
+    pub fn attach(&self, app: AppHandle, store: DecisionStore) {
+        if self.0.ui.set((app, store)).is_err() {
+            error!("network prompts were attached twice");
+        }
+    }
+
+    /// Hands the user's choice to the connections waiting on prompt `id`.
+    pub fn answer(&self, id: u64, choice: Choice) -> Result<(), String> {
+        let mut pending =
+            self.0.pending.lock().expect("prompts to not be poisoned");
+        let key = pending
+            .iter()
+            .find(|(_, prompt)| prompt.id == id)
+            .map(|(key, _)| key.clone())
+            .ok_or_else(|| format!("network prompt {id} is not open"))?;
+        let prompt = pending.remove(&key).expect("prompt to be pending");
+        drop(pending);
+        if choice == Choice::AllowAlways {
+            if let Some((_, store)) = self.0.ui.get() {
+                let (app, host) = &key;
+                if let Err(e) = store.allow(app, host) {
+                    error!("could not remember {host} for {app}: {e}");
+                }
+            }
+        }
+        for waiting in prompt.waiting {
+            let _ = waiting.send(choice);
+        }
+        Ok(())
+    }
+
+    async fn decide(&self, ctx: &ConnectionContext) -> FilterResult {
+        // asked about on the next pass, by the host the connection names
+        // rather than its bare IP
+        if ctx.will_peek {
+            return FilterResult::Allow;
+        }
+        // the app's own pages, the guard already narrowed loopback down to
+        // the sandbox server
+        if !ctx.resolved.is_empty()
+            && ctx.resolved.iter().all(|ip| ip.is_loopback())
+        {
+            return FilterResult::Allow;
+        }
+        let Some((handle, store)) = self.0.ui.get() else {
+            return FilterResult::Deny;
+        };
+        let Some(host) = ctx.addr.host() else {
+            return FilterResult::Deny;
+        };
+        // every app's webview logs in as its docId, anyone else is a stranger
+        let Some(app) = ctx.identity.username().map(str::to_string) else {
+            return FilterResult::Deny;
+        };
+        if store.is_allowed(&app, &host) {
+            return FilterResult::Allow;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let key = (app, host);
+        let id = {
+            let mut pending =
+                self.0.pending.lock().expect("prompts to not be poisoned");
+            match pending.get_mut(&key) {
+                Some(prompt) => {
+                    prompt.waiting.push(tx);
+                    prompt.id
+                }
+                None => {
+                    let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+                    let prompt = NetworkPrompt {
+                        id,
+                        app: key.0.clone(),
+                        host: key.1.clone(),
+                        port: ctx.addr.port(),
+                    };
+                    if let Err(e) = handle.emit("network-prompt", prompt) {
+                        error!("could not prompt for {}: {e}", key.1);
+                        return FilterResult::Deny;
+                    }
+                    let waiting = vec![tx];
+                    pending.insert(key.clone(), Pending { id, waiting });
+                    id
+                }
+            }
+        };
+        match timeout(self.0.timeout, rx).await {
+            Ok(Ok(Choice::AllowOnce | Choice::AllowAlways)) => {
+                FilterResult::Allow
+            }
+            Ok(Ok(Choice::Deny)) | Ok(Err(_)) => FilterResult::Deny,
+            Err(_) => {
+                info!("network prompt for {} timed out", key.1);
+                let mut pending =
+                    self.0.pending.lock().expect("prompts to not be poisoned");
+                // everyone else waiting on it is denied along with us
+                if pending.get(&key).is_some_and(|prompt| prompt.id == id) {
+                    pending.remove(&key);
+                    let _ = handle.emit("network-prompt-expired", id);
+                }
+                FilterResult::Deny
+            }
+        }
+    }
+}
+
+impl Filter for Prompts {
+    fn check<'a>(
+        &'a self,
+        ctx: &'a ConnectionContext,
+    ) -> BoxFuture<'a, FilterResult> {
+        Box::pin(self.decide(ctx))
+    }
+
+    fn name(&self) -> &str {
+        "prompt"
+    }
+}
@@ -23,3 +23,10 @@ export let SUBDOMAIN_WILDCARD_URL = new Promise<string>(async res => {
     await sentNonce
     invoke<string>(`get_sandbox_url${NONCE}`).then(res);
 })
+
+
+/** The docId of the app this window runs, its proxy login is made for it. */
+export let APP_DOC_ID = new Promise<string>(async res => {
+    await sentNonce
+    invoke<string>(`get_app_doc_id${NONCE}`).then(res);
+})
@@ -1,7 +1,8 @@
 import "./style.css"
 import { createSandbox } from "./sandbox.ts"
 import { InitParams } from "./proxy-sw/Interface.ts";
-import { SUBDOMAIN_WILDCARD_URL } from "./envs.ts";
+import { APP_DOC_ID, SUBDOMAIN_WILDCARD_URL } from "./envs.ts";
+import { showNetworkPrompts } from "./networkPrompts.ts";
 import { attachConsole } from '@tauri-apps/plugin-log';
 
 /*const _detach = await */ attachConsole();
@@ -48,4 +49,9 @@ async function init(
     setPort(port1)
   })
 }
-init("webxdc-test")
+// the window is opened for one app, "<appId>/<docId>"
+APP_DOC_ID.then(appDocId => {
+  showNetworkPrompts(appDocId)
+  const split = appDocId.indexOf("/")
+  init(appDocId.slice(0, split), appDocId.slice(split + 1))
+})
@@ -0,0 +1,71 @@
+import { invoke, transformCallback } from "@tauri-apps/api/core";
+import { NONCE, sentNonce } from "./envs.ts";
+
+/** What the proxy asks about when an app connects somewhere new. */
+interface NetworkPrompt {
+    id: number
+    /** The docId of the app asking. */
+    app: string
+    host: string
+    port: number
+}
+
+type Choice = "allow_once" | "allow_always" | "deny"
+
+/**
+ * Listens for `event`, the isolation hook only lets commands carrying the
+ * nonce through, so this goes around `@tauri-apps/api/event`.
+ */
+async function listen<T>(event: string, handler: (payload: T) => void) {
+    await sentNonce
+    await invoke(`plugin:event|listen${NONCE}`, {
+        event,
+        target: { kind: "Any" },
+        handler: transformCallback((e: { payload: T }) => handler(e.payload)),
+    })
+}
+
+/**
+ * Asks about the connections of the app with `appDocId`, until they are
+ * answered or the proxy gives up on them.
+ */
+export async function showNetworkPrompts(appDocId: string) {
+    const open = new Map<number, HTMLElement>()
+
+    await listen<NetworkPrompt>("network-prompt", prompt => {
+        if (prompt.app != appDocId || open.has(prompt.id)) return
+
+        const dialog = document.createElement("div")
+        dialog.className = "network-prompt"
+        dialog.setAttribute("role", "alertdialog")
+        const message = document.createElement("p")
+        message.textContent = `This app wants to connect to ${prompt.host}:${prompt.port}`
+        dialog.append(message)
+
+        const answers: [string, Choice][] = [
+            ["Allow once", "allow_once"],
+            ["Always allow", "allow_always"],
+            ["Deny", "deny"],
+        ]
+        for (const [label, choice] of answers) {
+            const button = document.createElement("button")
+            button.textContent = label
+            button.addEventListener("click", () => {
+                dismiss(prompt.id)
+                invoke(`answer_network_prompt${NONCE}`, { id: prompt.id, choice })
+                    .catch(e => console.warn("could not answer network prompt", e))
+            })
+            dialog.append(button)
+        }
+
+        open.set(prompt.id, dialog)
+        document.body.append(dialog)
+    })
+
+    await listen<number>("network-prompt-expired", dismiss)
+
+    function dismiss(id: number) {
+        open.get(id)?.remove()
+        open.delete(id)
+    }
+}